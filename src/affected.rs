@@ -0,0 +1,279 @@
+use std::ffi::{CString, OsString};
+use std::fs::{self, File};
+use std::io::Error as IOError;
+use std::ops::Range;
+use std::os::fd::AsRawFd;
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::combine;
+use crate::config::{Config, DM_LOCATION};
+use crate::error;
+use crate::kernel;
+use crate::mapfile::BlockStatus;
+use crate::prepare::prepare_maps;
+use crate::refresh::{find_device, MountedMap};
+use log::{error, info};
+
+/// Magic number of ext2, ext3 and ext4 in statfs
+const EXT_SUPER_MAGIC: i64 = 0xEF53;
+/// _IOWR('f', 11, struct fiemap)
+const FS_IOC_FIEMAP: libc::c_ulong = 0xC020660B;
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+/// Location of the data is not known yet, such as delayed allocation
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+/// Extents requested from the kernel at once
+const EXTENT_COUNT: usize = 64;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+    extents: [FiemapExtent; EXTENT_COUNT],
+}
+
+/// Gets where the data of a file is on its device, as ranges in bytes
+pub fn file_extents(file: &File) -> Result<Vec<Range<u64>>, IOError> {
+    let mut extents = Vec::new();
+    let mut start = 0;
+
+    loop {
+        let mut fiemap = Fiemap {
+            start,
+            length: u64::MAX - start,
+            flags: FIEMAP_FLAG_SYNC,
+            mapped_extents: 0,
+            extent_count: EXTENT_COUNT as u32,
+            reserved: 0,
+            extents: [FiemapExtent::default(); EXTENT_COUNT],
+        };
+        // SAFETY: fiemap is a valid struct fiemap with room for extent_count extents
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut fiemap) } < 0 {
+            return Err(IOError::last_os_error());
+        }
+
+        let mapped = &fiemap.extents[..fiemap.mapped_extents as usize];
+        for extent in mapped {
+            if extent.flags & FIEMAP_EXTENT_UNKNOWN == 0 {
+                extents.push(extent.physical..extent.physical + extent.length);
+            }
+        }
+        match mapped.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.logical + last.length
+            }
+            _ => return Ok(extents),
+        }
+    }
+}
+
+/// Counts the bytes of the extents that fall in the bad ranges, which must be sorted
+pub fn bad_bytes(extents: &[Range<u64>], bad: &[Range<u64>]) -> u64 {
+    extents
+        .iter()
+        .map(|extent| {
+            let first = bad.partition_point(|range| range.end <= extent.start);
+            bad[first..]
+                .iter()
+                .take_while(|range| range.start < extent.end)
+                .map(|range| range.end.min(extent.end) - range.start.max(extent.start))
+                .sum::<u64>()
+        })
+        .sum()
+}
+
+/// Finds where a device is mounted from /proc/self/mounts
+fn find_mount_point(device_name: &str) -> Option<OsString> {
+    let mounts = fs::read("/proc/self/mounts").ok()?;
+    let kernel_name = kernel::dm_devices()
+        .into_iter()
+        .find(|(name, _)| name == device_name)
+        .map(|(_, kernel_name)| format!("/dev/{kernel_name}"));
+    let device_path = format!("{DM_LOCATION}{device_name}");
+
+    mounts.split(|byte| *byte == b'\n').find_map(|line| {
+        let mut fields = line.split(|byte| *byte == b' ');
+        let source = fields.next()?;
+        let target = fields.next()?;
+        (source == device_path.as_bytes()
+            || Some(source) == kernel_name.as_deref().map(str::as_bytes))
+        .then(|| OsString::from_vec(unescape_mount(target)))
+    })
+}
+
+/// Decodes the octal escapes /proc/self/mounts uses for spaces and other characters
+pub fn unescape_mount(field: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        let escape = field.get(i + 1..i + 4).filter(|_| field[i] == b'\\');
+        match escape.and_then(|octal| u8::from_str_radix(std::str::from_utf8(octal).ok()?, 8).ok())
+        {
+            Some(byte) => {
+                output.push(byte);
+                i += 4;
+            }
+            None => {
+                output.push(field[i]);
+                i += 1;
+            }
+        }
+    }
+    output
+}
+
+/// Gets the magic number of the filesystem a path is on with statfs
+fn filesystem_magic(path: &Path) -> Option<i64> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    // SAFETY: statfs is plain data, so all zeros is valid
+    let mut statfs: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: statfs is given a valid C string and a struct to fill
+    if unsafe { libc::statfs(path.as_ptr(), &mut statfs) } != 0 {
+        return None;
+    }
+    Some(statfs.f_type as i64)
+}
+
+/// Checks every regular file on the filesystem, without crossing into other filesystems
+fn walk(
+    folder: &Path,
+    device: u64,
+    bad: &[Range<u64>],
+    affected: &mut Vec<(PathBuf, u64)>,
+    skipped: &mut usize,
+) {
+    let entries = match fs::read_dir(folder) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Unable to read folder {:?}: {:?}", folder, e);
+            *skipped += 1;
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.dev() == device => metadata,
+            Ok(_) => continue,
+            Err(e) => {
+                error!("Unable to read {:?}: {:?}", path, e);
+                *skipped += 1;
+                continue;
+            }
+        };
+
+        if metadata.is_dir() {
+            walk(&path, device, bad, affected, skipped);
+        } else if metadata.is_file() {
+            match File::open(&path).and_then(|file| file_extents(&file)) {
+                Ok(extents) => {
+                    let bytes = bad_bytes(&extents, bad);
+                    if bytes > 0 {
+                        affected.push((path, bytes));
+                    }
+                }
+                Err(e) => {
+                    error!("Unable to get extents of {:?}: {:?}", path, e);
+                    *skipped += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Lists the files on the filesystem mounted on a device that have data in areas
+/// the map file does not mark as finished, along with how many of their bytes are there.
+/// The device can be a partition device, like ddrm1p1
+pub fn affected(device_name: String, mount_point: Option<OsString>) {
+    let config = Config::read_config();
+    let parent = config
+        .entries()
+        .find(|(_, device)| {
+            device.dm_mount_point == device_name
+                || device.partitions.iter().any(|p| p.name == device_name)
+        })
+        .map(|(_, device)| device.dm_mount_point.clone())
+        .unwrap_or_else(|| error::no_affected_device(device_name.clone()));
+    let (_, device) = find_device(&config, &parent);
+    drop(config);
+
+    // the range of the whole device that the filesystem is in
+    let window = match device.partitions.iter().find(|p| p.name == device_name) {
+        Some(partition) => partition.start..partition.start + partition.size,
+        None => 0..u128::MAX,
+    };
+
+    let mount_point = mount_point
+        .or_else(|| {
+            (window.start == 0)
+                .then(|| device.filesystem.as_ref().map(|f| f.mount_point.clone()))
+                .flatten()
+        })
+        .or_else(|| find_mount_point(&device_name))
+        .unwrap_or_else(|| error::affected_error(error::NOT_MOUNTED_ERROR));
+    info!(
+        "Filesystem of {device_name} is mounted at {:?}",
+        mount_point
+    );
+
+    let MountedMap {
+        images,
+        options,
+        mount_options,
+    } = MountedMap::new(&device);
+    // areas are readable if any image rescued them
+    let mapfile = combine::or(&prepare_maps(&images, &options, &mount_options).0).unwrap();
+
+    // positions on the device start at the first block, then move to the start of the partition
+    let base = mapfile.blocks.first().map_or(0, |block| block.pos);
+    let bad: Vec<Range<u64>> = mapfile
+        .blocks
+        .iter()
+        .filter(|block| block.status != BlockStatus::Finished)
+        .map(|block| (block.pos - base).max(window.start)..(block.end() - base).min(window.end))
+        .filter(|range| range.start < range.end)
+        .map(|range| (range.start - window.start) as u64..(range.end - window.start) as u64)
+        .collect();
+
+    let root = Path::new(&mount_point);
+    let filesystem = fs::metadata(root).map(|metadata| metadata.dev());
+    let magic = filesystem_magic(root);
+    let filesystem = match (filesystem, magic) {
+        (Ok(filesystem), Some(EXT_SUPER_MAGIC)) => filesystem,
+        (Ok(_), Some(_)) => error::affected_error(error::UNSUPPORTED_FILESYSTEM_ERROR),
+        _ => error::affected_error(error::NOT_MOUNTED_ERROR),
+    };
+
+    let mut affected = Vec::new();
+    let mut skipped = 0;
+    walk(root, filesystem, &bad, &mut affected, &mut skipped);
+    affected.sort();
+
+    for (path, bytes) in &affected {
+        println!("{bytes:>14}  {}", path.to_string_lossy());
+    }
+    println!(
+        "{} files have data in areas that were not recovered",
+        affected.len()
+    );
+    if skipped > 0 {
+        println!("{skipped} files or folders could not be checked");
+    }
+}
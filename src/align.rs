@@ -0,0 +1,116 @@
+use crate::error;
+use crate::mapfile::{Block, BlockStatus, Mapfile};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// How extents that split a sector of the original disk are handled
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlignPolicy {
+    /// Report the extents as errors in the map file
+    Strict,
+    /// Round bad and untried extents outward so the whole sector becomes an error
+    ExpandBad,
+    /// Round bad and untried extents inward so the partially read sector is kept
+    ShrinkBad,
+}
+
+/// The sector size is zero or not a multiple of 512
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectorSizeError(pub u32);
+
+impl fmt::Display for SectorSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", error::SECTOR_SIZE_ERROR, self.0)
+    }
+}
+
+impl std::error::Error for SectorSizeError {}
+
+/// Checks that the sector size is one device mapper can address, a non-zero multiple of 512
+pub fn check_block_size(block_size: u32) -> Result<(), SectorSizeError> {
+    if block_size == 0 || !block_size.is_multiple_of(512) {
+        return Err(SectorSizeError(block_size));
+    }
+    Ok(())
+}
+
+/// Rounds every boundary touching a bad or untried extent to the sector size,
+/// merging neighbouring extents that end up with the same status.
+/// Returns the number of bytes that moved between finished and bad
+pub fn align(
+    mapfile: &mut Mapfile,
+    block_size: u32,
+    policy: AlignPolicy,
+) -> Result<u128, SectorSizeError> {
+    check_block_size(block_size)?;
+    if policy == AlignPolicy::Strict {
+        return Ok(0);
+    }
+
+    let sector = block_size as u128;
+    // A disk is always a whole number of sectors, so a partial sector at the end cannot be read
+    let total_end = round_down(mapfile.end(), sector);
+    let finished_before = finished_bytes(&mapfile.blocks, total_end);
+
+    let mut aligned: Vec<Block> = Vec::with_capacity(mapfile.blocks.len());
+    let mut start = mapfile.blocks.first().map(|block| block.pos).unwrap_or(0);
+
+    for (i, block) in mapfile.blocks.iter().enumerate() {
+        let end = match mapfile.blocks.get(i + 1) {
+            Some(next) => boundary(block, next, sector, policy),
+            None => total_end,
+        }
+        .min(total_end)
+        .max(start);
+
+        if end > start {
+            match aligned.last_mut() {
+                Some(prev) if prev.status == block.status => prev.size += end - start,
+                _ => aligned.push(Block {
+                    pos: start,
+                    size: end - start,
+                    status: block.status,
+                }),
+            }
+        }
+        start = end;
+    }
+
+    mapfile.blocks = aligned;
+
+    Ok(finished_before.abs_diff(finished_bytes(&mapfile.blocks, total_end)))
+}
+
+/// Works out where the boundary between two extents should fall
+fn boundary(prev: &Block, next: &Block, sector: u128, policy: AlignPolicy) -> u128 {
+    let position = prev.end();
+    let prev_finished = prev.status == BlockStatus::Finished;
+    let next_finished = next.status == BlockStatus::Finished;
+
+    match (policy, prev_finished, next_finished) {
+        // Finished extents map straight onto the image, so they are merged rather than moved
+        (_, true, true) => position,
+        (AlignPolicy::ExpandBad, true, false) => round_down(position, sector),
+        (AlignPolicy::ExpandBad, false, true) => round_up(position, sector),
+        (AlignPolicy::ShrinkBad, true, false) => round_up(position, sector),
+        _ => round_down(position, sector),
+    }
+}
+
+/// Counts the bytes in finished extents before limit
+fn finished_bytes(blocks: &[Block], limit: u128) -> u128 {
+    blocks
+        .iter()
+        .filter(|block| block.status == BlockStatus::Finished && block.pos < limit)
+        .map(|block| block.end().min(limit) - block.pos)
+        .sum()
+}
+
+fn round_down(position: u128, sector: u128) -> u128 {
+    position - position % sector
+}
+
+fn round_up(position: u128, sector: u128) -> u128 {
+    round_down(position + sector - 1, sector)
+}
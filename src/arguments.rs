@@ -1,4 +1,4 @@
-use clap::{IntoApp, Parser, Subcommand};
+use clap::{ArgEnum, IntoApp, Parser, Subcommand};
 use std::ffi::OsString;
 
 #[derive(Parser)]
@@ -8,34 +8,359 @@ pub struct Cli {
     pub command: Commands,
     #[clap(flatten)]
     pub verbose: clap_verbosity_flag::Verbosity,
+    /// Folder holding the list of mounted devices [default: /run/ddr-mount]
+    #[clap(long, global = true)]
+    pub state_dir: Option<OsString>,
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 pub enum Commands {
     /// Mounts the image but with the map file to present I/O errors.
     #[clap(long_about = "Mounts the image but with the map file to present I/O \
     errors. This is done by converting bad sectors and any areas not yet read \
     or skipped by ddrescue into I/O errors.")]
     Mount {
+        #[clap(short, long, required = true)]
+        /// Path to disk image. Can be repeated for several images of the same disk, each
+        /// with its own --map. Areas are read from the first image that rescued them
+        image: Vec<OsString>,
+        #[clap(short, long, required = true)]
+        /// Path to ddrescue map file, one for every --image in the same order
+        map: Vec<OsString>,
+        /// Sector size of disk that was imaged, a multiple of 512
+        #[clap(short, long, default_value_t = 512, parse(try_from_str = parse_block_size))]
+        block_size: u32,
+        /// Stop after reporting this many errors in the map file
+        #[clap(long, parse(try_from_str = parse_max_errors))]
+        max_errors: Option<usize>,
+        /// How to handle bad areas that do not fall on sector boundaries
+        #[clap(long, arg_enum, default_value_t = Align::Strict)]
+        align: Align,
+        /// How bad and untried areas are presented
+        #[clap(long, arg_enum, default_value_t = Treat::Error)]
+        bad_as: Treat,
+        /// Marker repeated in every sector of areas presented as pattern
+        #[clap(long, default_value = "BADSECTOR")]
+        pattern: String,
+        /// Map a ddrescue status to a target, like `--treat '*=linear'`. Can be repeated.
+        /// Status is one of ?, *, /, - or +. Target is error, linear, zero or pattern
+        #[clap(long, value_name = "STATUS=TARGET", parse(try_from_str = parse_treat))]
+        treat: Vec<(char, Treat)>,
+        /// Present only the size of the image if the map file is larger or smaller.
+        /// Any area of the image the map file does not cover is treated as untried
+        #[clap(long)]
+        clip: bool,
+        /// Position in the image where the device starts, in bytes
+        #[clap(long, default_value_t = 0, parse(try_from_str = parse_bytes))]
+        offset: u128,
+        /// Size of the device in bytes. Defaults to the rest of the image
+        #[clap(long, parse(try_from_str = parse_bytes))]
+        size: Option<u128>,
+        /// ddrescue domain map file. Only its finished areas are presented, with any
+        /// gaps between them treated as untried
+        #[clap(long)]
+        domain_map: Option<OsString>,
+        /// Also create a device for every partition in the image, like ddrm1p1
+        #[clap(long)]
+        partitions: bool,
+        /// Mount the filesystem on the device at this folder. It is unmounted along with the device
+        #[clap(long)]
+        mountpoint: Option<OsString>,
+        /// With --partitions, mount the filesystem of this partition, like 1 for ddrm1p1,
+        /// instead of the one on the whole device
+        #[clap(long, requires_all = &["partitions", "mountpoint"])]
+        partition: Option<u32>,
+        /// Filesystem type passed to mount. Detected with blkid by default
+        #[clap(long, requires = "mountpoint")]
+        fstype: Option<String>,
+        /// Options passed to mount, which always include ro. Defaults to ro, along with
+        /// noload for ext3 and ext4, norecovery for xfs and rescue=nologreplay for btrfs
+        #[clap(long, requires = "mountpoint")]
+        options: Option<String>,
+        /// Make the device writable, keeping every write in this file so the image is not
+        /// changed. The device is at /dev/mapper/ddrm#-cow. An existing file carries on
+        /// with the writes made before
+        #[clap(long, value_name = "FILE")]
+        cow: Option<OsString>,
+        /// Keep running after mounting, updating the device whenever the map file changes
+        #[clap(long)]
+        follow: bool,
+        /// With --follow, milliseconds the map file must go unchanged before it is read
+        #[clap(long, default_value_t = 2000)]
+        debounce: u64,
+    },
+    /// Copies the image into a new sparse image file, for tools that cannot use block devices
+    #[clap(
+        long_about = "Copies the image into a new sparse image file, for tools that cannot \
+    use block devices. Finished areas are copied, and bad and untried areas are left as holes \
+    that read as zeros, or filled with the pattern. A map file for the new image is written \
+    alongside it. Does not need root."
+    )]
+    Export {
         #[clap(short, long)]
         /// Path to disk image
         image: OsString,
         #[clap(short, long)]
         /// Path to ddrescue map file
         map: OsString,
-        /// Sector size of disk that was imaged
-        #[clap(short, long, default_value_t = 512)]
+        /// Path of the new image. It is overwritten if it exists
+        #[clap(short, long)]
+        output: OsString,
+        /// Path of the map file for the new image. Defaults to the new image with .map added
+        #[clap(long)]
+        output_map: Option<OsString>,
+        /// Sector size of disk that was imaged, a multiple of 512
+        #[clap(short, long, default_value_t = 512, parse(try_from_str = parse_block_size))]
         block_size: u32,
+        /// Stop after reporting this many errors in the map file
+        #[clap(long, parse(try_from_str = parse_max_errors))]
+        max_errors: Option<usize>,
+        /// How to handle bad areas that do not fall on sector boundaries
+        #[clap(long, arg_enum, default_value_t = Align::Strict)]
+        align: Align,
+        /// How bad and untried areas are written
+        #[clap(long, arg_enum, default_value_t = Fill::Zero)]
+        fill: Fill,
+        /// Marker repeated in every sector of areas filled with the pattern
+        #[clap(long, default_value = "BADSECTOR")]
+        pattern: String,
+        /// Export only the size of the image if the map file is larger or smaller.
+        /// Any area of the image the map file does not cover is treated as untried
+        #[clap(long)]
+        clip: bool,
+        /// Position in the image where the new image starts, in bytes
+        #[clap(long, default_value_t = 0, parse(try_from_str = parse_bytes))]
+        offset: u128,
+        /// Size of the new image in bytes. Defaults to the rest of the image
+        #[clap(long, parse(try_from_str = parse_bytes))]
+        size: Option<u128>,
+        /// ddrescue domain map file. Only its finished areas are copied, with any
+        /// gaps between them treated as untried
+        #[clap(long)]
+        domain_map: Option<OsString>,
     },
     /// Unmounts any image mounted by ddr-mount
     Unmount {
         /// Device previously mounted with ddr-mount mount (ex: ddrm0)
         device: String,
+        /// Keep the file holding the writes of a device mounted with --cow without asking
+        #[clap(long, conflicts_with = "discard-cow")]
+        keep_cow: bool,
+        /// Delete the file holding the writes of a device mounted with --cow without asking
+        #[clap(long)]
+        discard_cow: bool,
+    },
+    /// Reads the map file of a mounted image again, presenting any newly recovered areas
+    #[clap(
+        long_about = "Reads the map file of a mounted image again, presenting any newly \
+    recovered areas. The device is updated in place, so filesystems mounted on it do not \
+    need to be unmounted. Useful while ddrescue is still running."
+    )]
+    Refresh {
+        /// Device previously mounted with ddr-mount mount (ex: ddrm0)
+        device: String,
+    },
+    /// Updates a mounted image whenever its map file changes, until stopped
+    #[clap(
+        long_about = "Updates a mounted image whenever its map file changes, until \
+    stopped. This is the same as running refresh every time ddrescue writes the map file, \
+    and prints how many bytes became readable each time."
+    )]
+    Watch {
+        /// Device previously mounted with ddr-mount mount (ex: ddrm0)
+        device: String,
+        /// Milliseconds the map file must go unchanged before it is read
+        #[clap(long, default_value_t = 2000)]
+        debounce: u64,
+    },
+    /// Lists the files on a mounted ext2, ext3 or ext4 filesystem with data that was not recovered
+    #[clap(
+        long_about = "Lists the files on a mounted ext2, ext3 or ext4 filesystem with data \
+    in areas the map file does not mark as finished, along with how many bytes of each file \
+    are there. The filesystem must be mounted, either with --mountpoint when mounting the \
+    image or separately."
+    )]
+    Affected {
+        /// Device or partition device previously mounted with ddr-mount mount (ex: ddrm0, ddrm0p1)
+        device: String,
+        /// Where the filesystem is mounted. Found automatically by default
+        #[clap(long)]
+        mountpoint: Option<OsString>,
     },
     /// Unmounts all images mounted by ddr-mount
     UnmountAll,
+    /// Fixes the list of mounted images if devices were removed or added outside ddr-mount
+    #[clap(
+        long_about = "Fixes the list of mounted images if devices were removed or added \
+    outside ddr-mount, such as by dmsetup remove. Devices that no longer exist are removed \
+    from the list, devices mounted by ddr-mount that are missing are added back, and loop \
+    devices left behind by failed mounts are reported."
+    )]
+    Doctor,
     /// List mounted images and their mount points
-    List,
+    List {
+        /// Output format. json and tsv include every recorded field and the live state of the devices
+        #[clap(long, arg_enum, default_value_t = Format::Human)]
+        format: Format,
+    },
+    /// Summarizes a map file, like ddrescuelog -t
+    #[clap(
+        long_about = "Summarizes a map file, like ddrescuelog -t. Shows the bytes and \
+    areas of each status, how much was rescued, how many bad extents there are and how \
+    large they are, and the largest area that was read."
+    )]
+    Stats {
+        #[clap(short, long)]
+        /// Path to ddrescue map file
+        map: OsString,
+        /// Output format
+        #[clap(long, arg_enum, default_value_t = StatsFormat::Human)]
+        format: StatsFormat,
+    },
+    /// Combines or compares map files of the same disk, writing a new map file
+    #[clap(
+        long_about = "Combines or compares map files of the same disk, like ddrescuelog, \
+    writing a new map file that ddrescue can resume from or use as a domain map file. Map \
+    files of different lengths are treated as non-tried past their end."
+    )]
+    Mapfile {
+        #[clap(subcommand)]
+        operation: MapfileOperation,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum MapfileOperation {
+    /// Combines rescues of the same disk, keeping the status that got furthest in each area
+    Merge {
+        /// Map files to merge. The status line is taken from the first one
+        #[clap(required = true, min_values = 2)]
+        maps: Vec<OsString>,
+        /// Where to write the map file. Printed if not set
+        #[clap(short, long)]
+        output: Option<OsString>,
+    },
+    /// Marks areas finished if they are finished in any map file (rescued in either)
+    Or {
+        /// Map files to combine. The status line is taken from the first one
+        #[clap(required = true, min_values = 2)]
+        maps: Vec<OsString>,
+        /// Where to write the map file. Printed if not set
+        #[clap(short, long)]
+        output: Option<OsString>,
+    },
+    /// Marks areas finished only if they are finished in every map file (rescued in both)
+    And {
+        /// Map files to combine. The status line is taken from the first one
+        #[clap(required = true, min_values = 2)]
+        maps: Vec<OsString>,
+        /// Where to write the map file. Printed if not set
+        #[clap(short, long)]
+        output: Option<OsString>,
+    },
+    /// Marks areas whose status changed between two snapshots of a map file as finished
+    Diff {
+        /// Older snapshot of the map file
+        old: OsString,
+        /// Newer snapshot of the map file
+        new: OsString,
+        /// Where to write the map file. Printed if not set
+        #[clap(short, long)]
+        output: Option<OsString>,
+    },
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+pub enum Align {
+    /// Refuse to mount map files with bad areas that split a sector
+    Strict,
+    /// Mark the whole sector as bad
+    ExpandBad,
+    /// Mark the whole sector as read
+    ShrinkBad,
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+pub enum Fill {
+    /// Leave holes, which read as zeros and take no space
+    Zero,
+    /// Fill with the repeating pattern marker
+    Pattern,
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+pub enum Treat {
+    /// Reads fail with an I/O error
+    Error,
+    /// Reads return whatever data is in the image
+    Linear,
+    /// Reads return zeros
+    Zero,
+    /// Reads return the repeating pattern marker
+    Pattern,
+}
+
+/// Parses `--treat` values in the form of STATUS=TARGET
+fn parse_treat(value: &str) -> Result<(char, Treat), String> {
+    let (status, target) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected STATUS=TARGET, found {value}"))?;
+
+    let status = match status.parse::<char>() {
+        Ok(status) if "?*/-+".contains(status) => status,
+        _ => {
+            return Err(format!(
+                "unknown status {status}, expected one of ?, *, /, - or +"
+            ))
+        }
+    };
+
+    let target = Treat::from_str(target, true)
+        .map_err(|_| format!("unknown target {target}, expected error, linear, zero or pattern"))?;
+
+    Ok((status, target))
+}
+
+/// Parses `--max-errors`, which must be at least 1 as no map file could be read otherwise
+fn parse_max_errors(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(max) => Ok(max),
+        Err(e) => Err(format!("{e}")),
+    }
+}
+
+fn parse_block_size(value: &str) -> Result<u32, String> {
+    match value.parse::<u32>() {
+        Ok(size) if size == 0 || size % 512 != 0 => {
+            Err("must be a non-zero multiple of 512".to_string())
+        }
+        Ok(size) => Ok(size),
+        Err(e) => Err(format!("{e}")),
+    }
+}
+
+/// Parses a number of bytes in decimal, or hex beginning with 0x
+fn parse_bytes(value: &str) -> Result<u128, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u128::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+pub enum Format {
+    Human,
+    Json,
+    Tsv,
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+pub enum StatsFormat {
+    Human,
+    Json,
 }
 
 pub fn handle_arguments() -> Cli {
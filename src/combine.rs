@@ -0,0 +1,146 @@
+//! Combines map files of the same disk, like ddrescuelog does. The result is a new map file
+//! that covers the longest of the map files, with shorter ones treated as non-tried past their end
+
+use std::ops::Range;
+
+use crate::mapfile::{Block, BlockStatus, Mapfile};
+
+/// How far along the rescue of an area is. ddrescue moves areas from non-tried through
+/// trimming, scraping and retrying, until they are either finished or left as bad sectors
+fn progress(status: BlockStatus) -> u8 {
+    match status {
+        BlockStatus::NonTried => 0,
+        BlockStatus::NonTrimmed => 1,
+        BlockStatus::NonScraped => 2,
+        BlockStatus::BadSector => 3,
+        BlockStatus::Finished => 4,
+    }
+}
+
+fn least_progress(statuses: &[BlockStatus]) -> BlockStatus {
+    *statuses
+        .iter()
+        .min_by_key(|status| progress(**status))
+        .unwrap()
+}
+
+fn most_progress(statuses: &[BlockStatus]) -> BlockStatus {
+    *statuses
+        .iter()
+        .max_by_key(|status| progress(**status))
+        .unwrap()
+}
+
+/// Combines rescues of the same disk, such as by several tools or onto several images.
+/// Each area takes the status that got furthest, so resuming ddrescue with the result
+/// neither reads areas again that were read by any of them nor repeats their phases.
+/// The status line is taken from the first map file. Returns None if there are no map files
+pub fn merge(maps: &[Mapfile]) -> Option<Mapfile> {
+    combine(maps, most_progress)
+}
+
+/// Areas are finished if they are finished in any map file (rescued in either). Other areas
+/// take the status that got the least far, so areas are only bad if they are bad in all
+/// of them. The status line is taken from the first map file. Returns None if there are no map files
+pub fn or(maps: &[Mapfile]) -> Option<Mapfile> {
+    combine(maps, |statuses| {
+        if statuses.contains(&BlockStatus::Finished) {
+            BlockStatus::Finished
+        } else {
+            least_progress(statuses)
+        }
+    })
+}
+
+/// Areas are finished only if they are finished in every map file (rescued in both).
+/// Other areas take the status that got the least far, so areas that are bad in all
+/// of them stay bad. The status line is taken from the first map file.
+/// Returns None if there are no map files
+pub fn and(maps: &[Mapfile]) -> Option<Mapfile> {
+    combine(maps, least_progress)
+}
+
+/// Marks the areas whose status changed between two snapshots of the same map file as finished,
+/// and the rest as non-tried. The result can be used as a domain map file, such as to mount
+/// only the areas recovered since the old snapshot. The status line is taken from new
+pub fn diff(old: &Mapfile, new: &Mapfile) -> Mapfile {
+    let mut mapfile = combine(&[new.clone(), old.clone()], |statuses| {
+        if statuses[0] == statuses[1] {
+            BlockStatus::NonTried
+        } else {
+            BlockStatus::Finished
+        }
+    })
+    .unwrap();
+    mapfile.status = new.status;
+    mapfile
+}
+
+/// Walks through the map files together, choosing the status of each area with rule,
+/// which is given the statuses of the area in every map file in order
+fn combine(maps: &[Mapfile], rule: impl Fn(&[BlockStatus]) -> BlockStatus) -> Option<Mapfile> {
+    let status = maps.first()?.status;
+    let end = maps.iter().map(Mapfile::end).max().unwrap_or(0);
+    let maps: Vec<Mapfile> = maps
+        .iter()
+        .map(|map| {
+            let mut map = map.clone();
+            map.clip(end);
+            map
+        })
+        .collect();
+
+    let maps: Vec<&Mapfile> = maps.iter().collect();
+
+    let mut blocks: Vec<Block> = Vec::new();
+    walk(&maps, |range, statuses| {
+        let status = rule(statuses);
+        match blocks.last_mut() {
+            Some(last) if last.status == status => last.size += range.end - range.start,
+            _ => blocks.push(Block {
+                pos: range.start,
+                size: range.end - range.start,
+                status,
+            }),
+        }
+    });
+
+    Some(Mapfile { status, blocks })
+}
+
+/// Splits the map files into areas where none of them change status, visiting each area
+/// with the statuses it has in every map file in order. The map files must cover the same range
+pub(crate) fn walk(maps: &[&Mapfile], mut visit: impl FnMut(Range<u128>, &[BlockStatus])) {
+    let (start, end) = match maps.first() {
+        Some(first) => (
+            first.blocks.first().map_or(0, |block| block.pos),
+            first.end(),
+        ),
+        None => return,
+    };
+    assert!(
+        maps.iter().all(
+            |map| map.blocks.first().map_or(0, |block| block.pos) == start && map.end() == end
+        ),
+        "map files must cover the same range"
+    );
+
+    let mut index = vec![0; maps.len()];
+    let mut statuses = Vec::with_capacity(maps.len());
+    let mut pos = start;
+
+    while pos < end {
+        statuses.clear();
+        let mut next = end;
+        for (map, i) in maps.iter().zip(&mut index) {
+            while map.blocks[*i].end() <= pos {
+                *i += 1;
+            }
+            statuses.push(map.blocks[*i].status);
+            next = next.min(map.blocks[*i].end());
+        }
+
+        visit(pos..next, &statuses);
+        pos = next;
+    }
+}
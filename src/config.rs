@@ -1,16 +1,28 @@
+use crate::align::AlignPolicy;
+use crate::doctor;
 use crate::error::{self, set_config_error};
+use crate::filesystem::Filesystem;
+use crate::kernel;
+use crate::table::{pattern_stack_size, MountOptions, PatternDevice};
 use indexmap::IndexMap;
 use log::{error, info};
 use serde::{Deserialize, Serialize};
-use std::os::unix::prelude::*;
+use std::os::unix::{fs::DirBuilderExt, prelude::*};
 use std::{
     ffi::OsString,
-    fs::File,
-    io::{ErrorKind, Read},
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
 };
 
 pub const DEVICE_NAME: &str = "ddrm";
+/// Folder in the temp folder used by earlier versions
 pub const CONFIG_FOLDER: &str = "ddr-mount";
+/// Default folder holding the configuration and pattern files
+pub const STATE_FOLDER: &str = "/run/ddr-mount";
+pub const CONFIG_FILE: &str = "config.json";
+pub const LOCK_FILE: &str = "config.lock";
 pub const DM_LOCATION: &str = "/dev/mapper/";
 
 pub struct Device {
@@ -18,6 +30,11 @@ pub struct Device {
     pub device_mount_point: String,
     entry: u32,
     image_mount: ImageLocation,
+    pattern: Option<PatternLocation>,
+    filesystem: Option<Filesystem>,
+    partitions: Vec<PartitionDevice>,
+    layers: Vec<ImageLayer>,
+    overlay: Option<Overlay>,
 }
 
 #[derive(Clone)]
@@ -25,6 +42,65 @@ pub struct ImageLocation {
     image_path: String,
 }
 
+/// Pattern file used to fill bad areas and the loop device it is attached to
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PatternLocation {
+    pub file: OsString,
+    pub mount_point: String,
+    /// Devices stacked on the loop device so that pattern extents fit in one line, largest last
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+impl PatternLocation {
+    /// Device that pattern targets read from
+    pub fn device(&self) -> PatternDevice {
+        match self.devices.last() {
+            Some(name) => PatternDevice {
+                device: format!("{DM_LOCATION}{name}"),
+                size: pattern_stack_size(self.devices.len() as u32),
+            },
+            None => PatternDevice::new(&self.mount_point),
+        }
+    }
+}
+
+/// Device for a partition of the image, stacked on the device for the whole image
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PartitionDevice {
+    /// Name of the device, like ddrm1p1
+    pub name: String,
+    pub number: u32,
+    /// Position of the partition in the device for the whole image, in bytes
+    pub start: u128,
+    pub size: u128,
+}
+
+/// A further image of the same disk, read where the images before it were not rescued
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageLayer {
+    pub image_file: OsString,
+    /// image file mount point
+    pub image_mount_point: String,
+    pub map_file: OsString,
+    /// SHA-256 of the map file when it was mounted
+    pub map_hash: Option<String>,
+    /// Modification time of the map file when it was mounted, in seconds since the Unix epoch
+    pub map_modified: Option<u64>,
+}
+
+/// Writable snapshot stacked on a device, holding every write made to it
+/// so that the image is never changed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Overlay {
+    /// Name of the snapshot device, like ddrm1-cow
+    pub name: String,
+    /// File the writes are stored in
+    pub file: OsString,
+    /// Loop device of the file
+    pub loop_device: String,
+}
+
 impl Device {
     pub fn get_entry(&self) -> u32 {
         self.entry
@@ -33,6 +109,26 @@ impl Device {
     pub fn get_image_location(&self) -> String {
         self.image_mount.image_path.clone()
     }
+
+    pub fn get_pattern_location(&self) -> Option<PatternLocation> {
+        self.pattern.clone()
+    }
+
+    pub fn get_filesystem(&self) -> Option<Filesystem> {
+        self.filesystem.clone()
+    }
+
+    pub fn get_partitions(&self) -> &[PartitionDevice] {
+        &self.partitions
+    }
+
+    pub fn get_layers(&self) -> &[ImageLayer] {
+        &self.layers
+    }
+
+    pub fn get_overlay(&self) -> Option<&Overlay> {
+        self.overlay.as_ref()
+    }
 }
 
 pub struct DeviceIterator<'a> {
@@ -52,6 +148,11 @@ impl Iterator for DeviceIterator<'_> {
                 image_mount: ImageLocation {
                     image_path: entry.1.image_mount_point.clone(),
                 },
+                pattern: entry.1.pattern.clone(),
+                filesystem: entry.1.filesystem.clone(),
+                partitions: entry.1.partitions.clone(),
+                layers: entry.1.layers.clone(),
+                overlay: entry.1.overlay.clone(),
             }),
             None => None,
         }
@@ -60,122 +161,502 @@ impl Iterator for DeviceIterator<'_> {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConfigEntry {
-    image_file: OsString,
+    pub image_file: OsString,
     /// image file mount point
-    image_mount_point: String,
-    dm_mount_point: String,
+    pub image_mount_point: String,
+    pub dm_mount_point: String,
+    #[serde(default)]
+    pub pattern: Option<PatternLocation>,
+    /// Not recorded by versions before 1.2
+    #[serde(default)]
+    pub block_size: Option<u32>,
+    #[serde(default)]
+    pub map_file: Option<OsString>,
+    /// Seconds since the Unix epoch
+    #[serde(default)]
+    pub mounted_at: Option<u64>,
+    /// SHA-256 of the map file when it was mounted
+    #[serde(default)]
+    pub map_hash: Option<String>,
+    /// Modification time of the map file when it was mounted, in seconds since the Unix epoch
+    #[serde(default)]
+    pub map_modified: Option<u64>,
+    #[serde(default)]
+    pub align: Option<AlignPolicy>,
+    /// Options the device was mounted with, so that its table can be created again
+    #[serde(default)]
+    pub options: Option<MountOptions>,
+    /// Filesystem mounted on the device, unmounted along with it
+    #[serde(default)]
+    pub filesystem: Option<Filesystem>,
+    /// Devices for the partitions of the image, removed before the device
+    #[serde(default)]
+    pub partitions: Vec<PartitionDevice>,
+    /// Further images of the same disk after image_file, in order of precedence
+    #[serde(default)]
+    pub layers: Vec<ImageLayer>,
+    /// Writable snapshot stacked on the device, removed before it
+    #[serde(default)]
+    pub overlay: Option<Overlay>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Config(#[serde(with = "indexmap::serde_seq")] IndexMap<u32, ConfigEntry>);
+/// Output format of the list subcommand
+#[derive(Clone, Copy)]
+pub enum ListFormat {
+    Human,
+    Json,
+    Tsv,
+}
 
-impl Config {
-    pub fn read_config() -> Config {
-        let mut temp = std::env::temp_dir();
-        temp.push(CONFIG_FOLDER);
-        std::fs::create_dir_all(&temp).unwrap_or_else(|e| {
-            error!("Unable to create configuration {:?}", e);
-            error::set_config_error()
-        });
-        temp.push("config.json");
+/// A device in the machine-readable list output, with its live state from the kernel
+#[derive(Serialize)]
+struct ListEntry<'a> {
+    dm_name: &'a str,
+    dm_path: String,
+    image_file: &'a [u8],
+    loop_device: &'a str,
+    block_size: Option<u32>,
+    map_file: Option<&'a [u8]>,
+    mounted_at: Option<u64>,
+    map_hash: Option<&'a str>,
+    map_modified: Option<u64>,
+    align: Option<AlignPolicy>,
+    options: Option<&'a MountOptions>,
+    pattern_loop_device: Option<&'a str>,
+    mount_point: Option<&'a [u8]>,
+    fstype: Option<&'a str>,
+    partitions: &'a [PartitionDevice],
+    layers: Vec<ListLayer<'a>>,
+    /// Path of the writable snapshot device
+    overlay_device: Option<String>,
+    overlay_file: Option<&'a [u8]>,
+    /// Device mapper device exists
+    dm_active: bool,
+    dm_suspended: bool,
+    /// File the loop device is currently attached to
+    loop_backing_file: Option<Vec<u8>>,
+}
 
-        info!("Configuration location: {:?}", temp);
+/// A further image of a device in the machine-readable list output
+#[derive(Serialize)]
+struct ListLayer<'a> {
+    image_file: &'a [u8],
+    loop_device: &'a str,
+    map_file: &'a [u8],
+    map_hash: Option<&'a str>,
+    map_modified: Option<u64>,
+    loop_backing_file: Option<Vec<u8>>,
+}
 
-        let file = File::options().read(true).mode(0o664).open(&temp);
+impl<'a> ListEntry<'a> {
+    fn new(entry: &'a ConfigEntry) -> ListEntry<'a> {
+        let dm_state = kernel::dm_state(&entry.dm_mount_point);
+        ListEntry {
+            dm_name: &entry.dm_mount_point,
+            dm_path: format!("{DM_LOCATION}{}", entry.dm_mount_point),
+            image_file: entry.image_file.as_bytes(),
+            loop_device: &entry.image_mount_point,
+            block_size: entry.block_size,
+            map_file: entry.map_file.as_ref().map(|file| file.as_bytes()),
+            mounted_at: entry.mounted_at,
+            map_hash: entry.map_hash.as_deref(),
+            map_modified: entry.map_modified,
+            align: entry.align,
+            options: entry.options.as_ref(),
+            pattern_loop_device: entry.pattern.as_ref().map(|p| p.mount_point.as_str()),
+            mount_point: entry.filesystem.as_ref().map(|f| f.mount_point.as_bytes()),
+            fstype: entry.filesystem.as_ref().and_then(|f| f.fstype.as_deref()),
+            partitions: &entry.partitions,
+            layers: entry
+                .layers
+                .iter()
+                .map(|layer| ListLayer {
+                    image_file: layer.image_file.as_bytes(),
+                    loop_device: &layer.image_mount_point,
+                    map_file: layer.map_file.as_bytes(),
+                    map_hash: layer.map_hash.as_deref(),
+                    map_modified: layer.map_modified,
+                    loop_backing_file: kernel::loop_backing_file(&layer.image_mount_point)
+                        .map(OsString::into_vec),
+                })
+                .collect(),
+            overlay_device: entry
+                .overlay
+                .as_ref()
+                .map(|overlay| format!("{DM_LOCATION}{}", overlay.name)),
+            overlay_file: entry
+                .overlay
+                .as_ref()
+                .map(|overlay| overlay.file.as_bytes()),
+            dm_active: dm_state.is_some(),
+            dm_suspended: dm_state.is_some_and(|state| state.suspended),
+            loop_backing_file: kernel::loop_backing_file(&entry.image_mount_point)
+                .map(OsString::into_vec),
+        }
+    }
 
-        if let Err(error) = file {
-            if let ErrorKind::NotFound = error.kind() {
-                Config(IndexMap::new())
-            } else {
-                error!("Configuration open error {:?}", error);
-                error::read_config_error();
+    /// Fields of the entry as tab separated values, in the same order as [`TSV_HEADER`].
+    /// Mount options are only included in the JSON output, and partitions are listed by name.
+    /// Further images are listed by their loop devices
+    fn to_tsv(&self) -> String {
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        [
+            self.dm_name.to_string(),
+            self.dm_path.clone(),
+            escape_tsv(self.image_file),
+            self.loop_device.to_string(),
+            optional(self.block_size.map(|size| size.to_string())),
+            optional(self.map_file.map(escape_tsv)),
+            optional(self.mounted_at.map(|time| time.to_string())),
+            optional(self.map_hash.map(str::to_string)),
+            optional(self.map_modified.map(|time| time.to_string())),
+            optional(self.pattern_loop_device.map(str::to_string)),
+            self.dm_active.to_string(),
+            self.dm_suspended.to_string(),
+            optional(self.loop_backing_file.as_deref().map(escape_tsv)),
+            optional(self.mount_point.map(escape_tsv)),
+            optional(self.fstype.map(str::to_string)),
+            self.partitions
+                .iter()
+                .map(|partition| partition.name.as_str())
+                .collect::<Vec<_>>()
+                .join(","),
+            self.layers
+                .iter()
+                .map(|layer| layer.loop_device)
+                .collect::<Vec<_>>()
+                .join(","),
+            optional(self.overlay_device.clone()),
+            optional(self.overlay_file.map(escape_tsv)),
+        ]
+        .join("\t")
+    }
+}
+
+const TSV_HEADER: &str = "dm_name\tdm_path\timage_file\tloop_device\tblock_size\tmap_file\t\
+mounted_at\tmap_hash\tmap_modified\tpattern_loop_device\tdm_active\tdm_suspended\tloop_backing_file\t\
+mount_point\tfstype\tpartitions\tlayer_loop_devices\toverlay_device\toverlay_file";
+
+/// Escapes backslashes, tabs, newlines and any bytes that are not valid UTF-8,
+/// so that paths cannot break the columns
+fn escape_tsv(bytes: &[u8]) -> String {
+    let mut output = String::new();
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => output.push_str("\\\\"),
+                '\t' => output.push_str("\\t"),
+                '\n' => output.push_str("\\n"),
+                '\r' => output.push_str("\\r"),
+                c => output.push(c),
             }
+        }
+        for byte in chunk.invalid() {
+            output.push_str(&format!("\\x{byte:02x}"));
+        }
+    }
+    output
+}
+
+/// Version of the configuration file written by this version of ddr-mount
+pub const CONFIG_VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize)]
+struct VersionedConfig {
+    version: u32,
+    #[serde(with = "indexmap::serde_seq")]
+    devices: IndexMap<u32, ConfigEntry>,
+}
+
+/// Written by versions before 1.2 as a list of devices, without a version
+#[derive(Deserialize)]
+struct LegacyConfig(#[serde(with = "indexmap::serde_seq")] IndexMap<u32, ConfigEntry>);
+
+pub struct Config {
+    devices: IndexMap<u32, ConfigEntry>,
+    /// Lock file, held until the configuration is dropped
+    _lock: Option<File>,
+    /// Configuration in the temp folder used by earlier versions, removed on commit
+    legacy_file: Option<PathBuf>,
+}
+
+impl Config {
+    /// Reads the configuration for reading only, holding a shared lock so that
+    /// it is not read in the middle of a change
+    pub fn read_config() -> Config {
+        let folder = state_folder();
+        // nothing can have written the configuration if its folder does not exist. Otherwise
+        // the lock file is created if needed, so a change starting now waits for the read
+        let lock = if folder.exists() {
+            Some(open_lock(&folder, false))
         } else {
-            let mut config_entries = String::new();
-            let file_size = file
-                .unwrap()
-                .read_to_string(&mut config_entries)
-                .unwrap_or_else(|e| {
-                    error!("Unable to convert configuration to file, {:?}", e);
-                    set_config_error()
-                });
-
-            if file_size == 0 {
-                config_entries = "{}".to_string();
+            None
+        };
+        Config::load(&folder, lock)
+    }
+
+    /// Reads the configuration for changing, holding an exclusive lock until dropped
+    /// so that other instances of ddr-mount cannot change it at the same time.
+    /// Changes are only saved by [`Config::commit`]
+    pub fn lock_config() -> Config {
+        let folder = config_folder();
+        let lock = open_lock(&folder, true);
+        Config::load(&folder, Some(lock))
+    }
+
+    fn load(folder: &Path, lock: Option<File>) -> Config {
+        let path = folder.join(CONFIG_FILE);
+        info!("Configuration location: {:?}", path);
+
+        let mut legacy_file = None;
+        let devices = match read_config_file(&path) {
+            Some(devices) => devices,
+            None => {
+                let mut legacy = std::env::temp_dir();
+                legacy.push(CONFIG_FOLDER);
+                legacy.push(CONFIG_FILE);
+                match read_config_file(&legacy) {
+                    Some(devices) => {
+                        info!("Importing configuration from {:?}", legacy);
+                        legacy_file = Some(legacy);
+                        devices
+                    }
+                    None => IndexMap::new(),
+                }
             }
-            let config: Config = serde_json::from_str(&config_entries).unwrap_or_else(|e| {
-                error!("Unable to parse error {:?}", e);
-                set_config_error()
-            });
+        };
 
-            config
+        Config {
+            devices,
+            _lock: lock,
+            legacy_file,
         }
     }
 
-    pub fn iter_mut(&mut self) -> DeviceIterator {
+    pub fn iter_mut(&mut self) -> DeviceIterator<'_> {
         DeviceIterator {
-            iterator: self.0.iter_mut(),
-        }
-    }
-
-    pub fn write_device(
-        &mut self,
-        image_path: OsString,
-        entry: u32,
-        image_mount: String,
-        dm_mount_point: String,
-    ) {
-        self.0.insert(
-            entry,
-            ConfigEntry {
-                image_file: image_path,
-                image_mount_point: image_mount,
-                dm_mount_point,
-            },
-        );
+            iterator: self.devices.iter_mut(),
+        }
+    }
+
+    pub fn entries(&self) -> indexmap::map::Iter<'_, u32, ConfigEntry> {
+        self.devices.iter()
+    }
+
+    /// Gets the lowest device number that is not in the configuration or used by the kernel
+    pub fn next_entry(&self) -> u32 {
+        (1..=u32::MAX)
+            .find(|num| {
+                !self.devices.contains_key(num)
+                    && kernel::dm_state(&format!("{DEVICE_NAME}{num}")).is_none()
+            })
+            .unwrap_or_else(|| error::set_config_error())
     }
 
-    pub fn write_config(&mut self) {
-        let mut temp = std::env::temp_dir();
-        temp.push(CONFIG_FOLDER);
-        temp.push("config.json");
-        std::fs::write(temp, serde_json::to_string(self).unwrap().as_bytes()).unwrap_or_else(|e| {
+    pub fn write_device(&mut self, entry: u32, device: ConfigEntry) {
+        self.devices.insert(entry, device);
+    }
+
+    /// Removes a device from the configuration, returning it if it was there
+    pub fn take_device(&mut self, entry: u32) -> Option<ConfigEntry> {
+        self.devices.shift_remove(&entry)
+    }
+
+    /// Saves the configuration. It is written to a temporary file first and then renamed
+    /// over the old one, so it is never left half written
+    pub fn commit(&mut self) {
+        self.devices.sort_keys();
+
+        let folder = config_folder();
+        let path = folder.join(CONFIG_FILE);
+        let temp = folder.join(format!("{CONFIG_FILE}.tmp"));
+        let config = VersionedConfig {
+            version: CONFIG_VERSION,
+            devices: self.devices.clone(),
+        };
+
+        let result = File::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o644)
+            .open(&temp)
+            .and_then(|mut file| {
+                file.write_all(serde_json::to_string(&config).unwrap().as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&temp, &path));
+
+        result.unwrap_or_else(|e| {
             error!("Unable to write configuration {:?}", e);
             set_config_error()
         });
 
+        if let Some(legacy) = self.legacy_file.take() {
+            if let Err(e) = fs::remove_file(&legacy) {
+                error!("Unable to remove old configuration {:?}: {:?}", legacy, e);
+            }
+        }
+
         info!("Configuration file written");
     }
 
     pub fn remove_device(&mut self, entry: u32) {
-        if self.0.remove(&entry).is_none() {
+        if self.devices.remove(&entry).is_none() {
             error::set_config_error();
         }
     }
 
     pub fn clear_devices(&mut self) {
-        self.0.clear();
+        self.devices.clear();
         info!("All devices cleared");
     }
 }
 
-impl Drop for Config {
-    fn drop(&mut self) {
-        self.0.sort_keys();
-        self.write_config();
+/// Opens the lock file in the folder and waits for the lock
+fn open_lock(folder: &Path, exclusive: bool) -> File {
+    let path = folder.join(LOCK_FILE);
+    let file = File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o644)
+        .open(&path);
+    // users other than root can still read the configuration once the lock file exists
+    let file = match file {
+        Err(e) if !exclusive && e.kind() == ErrorKind::PermissionDenied => File::open(&path),
+        file => file,
+    };
+
+    let result = file.and_then(|file| {
+        if exclusive {
+            file.lock()?;
+        } else {
+            file.lock_shared()?;
+        }
+        Ok(file)
+    });
+
+    result.unwrap_or_else(|e| {
+        error!("Unable to lock configuration {:?}: {:?}", path, e);
+        if exclusive {
+            set_config_error()
+        } else {
+            error::read_config_error()
+        }
+    })
+}
+
+/// Reads a configuration file in any version, or None if it does not exist
+fn read_config_file(path: &Path) -> Option<IndexMap<u32, ConfigEntry>> {
+    let mut config_entries = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == ErrorKind::NotFound => return None,
+        Err(error) => {
+            error!("Configuration open error {:?}", error);
+            error::read_config_error();
+        }
+    };
+
+    if config_entries.trim().is_empty() {
+        config_entries = "[]".to_string();
+    }
+    let (version, devices) = parse_config(&config_entries).unwrap_or_else(|e| parse_error(e));
+    if version > CONFIG_VERSION {
+        error!("Configuration version {version} is newer than supported version {CONFIG_VERSION}");
+        error::read_config_error();
     }
+    if version < CONFIG_VERSION {
+        info!("Migrating configuration from version {version} to version {CONFIG_VERSION}");
+    }
+    Some(devices)
+}
+
+/// Parses a configuration file in any version, returning its version and devices.
+/// Files written before 1.2 are a list of devices and count as version 1
+pub fn parse_config(
+    contents: &str,
+) -> Result<(u32, IndexMap<u32, ConfigEntry>), serde_json::Error> {
+    if contents.trim_start().starts_with('[') {
+        let config: LegacyConfig = serde_json::from_str(contents)?;
+        Ok((1, config.0))
+    } else {
+        let config: VersionedConfig = serde_json::from_str(contents)?;
+        Ok((config.version, config.devices))
+    }
+}
+
+fn parse_error(error: serde_json::Error) -> ! {
+    error!("Unable to parse error {:?}", error);
+    set_config_error()
+}
+
+static STATE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Changes the folder holding the configuration and pattern files from [`STATE_FOLDER`].
+/// Must be called before the configuration is read
+pub fn set_state_folder(folder: PathBuf) {
+    if STATE_DIR.set(folder).is_err() {
+        error!("State folder was already set");
+    }
+}
+
+/// Gets the folder holding the configuration and pattern files
+pub fn state_folder() -> PathBuf {
+    STATE_DIR
+        .get()
+        .cloned()
+        .unwrap_or_else(|| PathBuf::from(STATE_FOLDER))
+}
+
+/// Gets the folder holding the configuration and pattern files, creating it if needed
+pub fn config_folder() -> PathBuf {
+    let folder = state_folder();
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(&folder)
+        .unwrap_or_else(|e| {
+            error!("Unable to create configuration {:?}", e);
+            error::set_config_error()
+        });
+    folder
 }
 
-pub fn list_devices() {
+pub fn list_devices(format: ListFormat) {
     let mut config = Config::read_config();
+    // the configuration is only read here, so the changes are shown but not saved.
+    // The note goes to stderr to keep the json and tsv output intact
+    let report = doctor::reconcile(&mut config);
+    if !report.is_empty() {
+        eprintln!("The list is out of date. Run sudo ddr-mount doctor to fix it");
+    }
+
+    match format {
+        ListFormat::Human => (),
+        ListFormat::Json => {
+            let entries: Vec<ListEntry> = config.devices.values().map(ListEntry::new).collect();
+            println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+            return;
+        }
+        ListFormat::Tsv => {
+            println!("{TSV_HEADER}");
+            for entry in config.devices.values() {
+                println!("{}", ListEntry::new(entry).to_tsv());
+            }
+            return;
+        }
+    }
 
     let mut max_size = 0;
 
     for device in config.iter_mut() {
-        let image = device.image_file_path.to_string_lossy();
-        let count = image.chars().count();
-        if count > max_size {
-            max_size = count;
+        let layers = device.get_layers().iter().map(|layer| &layer.image_file);
+        for image in [&device.image_file_path].into_iter().chain(layers) {
+            let count = image.to_string_lossy().chars().count();
+            if count > max_size {
+                max_size = count;
+            }
         }
     }
     max_size += 1;
@@ -186,24 +667,31 @@ pub fn list_devices() {
             name.to_string() + &" ".repeat(max_size - count)
         } else {
             let name = "Unknown Image";
-            name.to_string() + &" ".repeat(max_size - name.chars().count())
+            name.to_string() + &" ".repeat(max_size.saturating_sub(name.chars().count()))
         };
 
-        println!("{image} => {DM_LOCATION}{}", device.device_mount_point);
-    }
-
-    std::mem::forget(config);
-}
-
-pub fn get_next_devices() -> u32 {
-    let config = &Config::read_config().0;
-    let mut next_device = 0;
-    for num in 1..=u32::MAX {
-        if config.get(&num).is_some() {
-            continue;
+        let overlay = device
+            .get_overlay()
+            .map(|overlay| format!(" (writable at {DM_LOCATION}{})", overlay.name))
+            .unwrap_or_default();
+        match device.get_filesystem() {
+            Some(filesystem) => println!(
+                "{image} => {DM_LOCATION}{}{overlay} on {}",
+                device.device_mount_point,
+                filesystem.mount_point.to_string_lossy()
+            ),
+            None => println!(
+                "{image} => {DM_LOCATION}{}{overlay}",
+                device.device_mount_point
+            ),
+        }
+        // further images are listed under the first, as they are read from by the same device
+        for layer in device.get_layers() {
+            println!("  + {}", layer.image_file.to_string_lossy());
+        }
+        let indent = " ".repeat(max_size + 3);
+        for partition in device.get_partitions() {
+            println!("{indent}{DM_LOCATION}{}", partition.name);
         }
-        next_device = num;
-        break;
     }
-    next_device
 }
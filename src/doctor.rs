@@ -0,0 +1,235 @@
+use std::ffi::OsString;
+use std::path::Path;
+
+use crate::config::{
+    self, Config, ConfigEntry, Overlay, PartitionDevice, PatternLocation, DEVICE_NAME, DM_LOCATION,
+};
+use crate::kernel::{self, LoopDevice};
+use log::info;
+
+/// Differences between the configuration and the devices the kernel has
+#[derive(Default)]
+pub struct Report {
+    /// Devices in the configuration that no longer exist, which were removed from it
+    pub dropped: Vec<ConfigEntry>,
+    /// Devices missing from the configuration that were identified and added to it
+    pub adopted: Vec<String>,
+    /// Devices named like ours that are missing from the configuration and could not be identified
+    pub unknown: Vec<String>,
+    /// Loop devices attached to an image, overlay or pattern file that nothing reads from,
+    /// usually left behind by a mount that failed part way
+    pub leftover: Vec<LoopDevice>,
+}
+
+impl Report {
+    pub fn is_empty(&self) -> bool {
+        self.dropped.is_empty()
+            && self.adopted.is_empty()
+            && self.unknown.is_empty()
+            && self.leftover.is_empty()
+    }
+
+    /// Prints the changes made to the configuration
+    pub fn print_changes(&self) {
+        for entry in &self.dropped {
+            println!(
+                "Removed {} from the list as it no longer exists",
+                entry.dm_mount_point
+            );
+        }
+        for name in &self.adopted {
+            println!("Added {name} to the list as it was missing");
+        }
+    }
+
+    /// Prints the changes along with any problems that need to be fixed by hand
+    pub fn print(&self) {
+        if self.is_empty() {
+            println!("No problems found");
+            return;
+        }
+
+        self.print_changes();
+        for name in &self.unknown {
+            println!(
+                "{DM_LOCATION}{name} is not in the list and its image could not be found. \
+                Remove it with: dmsetup remove {name}"
+            );
+        }
+        for device in &self.leftover {
+            println!(
+                "{} is attached to {} but not in use. Detach it with: losetup -d {}",
+                device.device,
+                device.backing_file.to_string_lossy(),
+                device.device
+            );
+        }
+    }
+}
+
+/// Brings the configuration in line with the devices the kernel has. Changes are only
+/// saved once the configuration is committed
+pub fn reconcile(config: &mut Config) -> Report {
+    let mut report = Report::default();
+
+    let stale: Vec<u32> = config
+        .entries()
+        .filter(|(_, entry)| kernel::dm_state(&entry.dm_mount_point).is_none())
+        .map(|(entry, _)| *entry)
+        .collect();
+    for entry in stale {
+        if let Some(entry) = config.take_device(entry) {
+            info!("{} no longer exists", entry.dm_mount_point);
+            report.dropped.push(entry);
+        }
+    }
+
+    let state_folder = config::state_folder();
+    for (name, _) in kernel::dm_devices() {
+        let entry = match name.strip_prefix(DEVICE_NAME).map(str::parse::<u32>) {
+            Some(Ok(entry)) => entry,
+            _ => continue,
+        };
+        if config.entries().any(|(_, e)| e.dm_mount_point == name) {
+            continue;
+        }
+
+        match identify(&name, &state_folder) {
+            Some(device) => {
+                info!("Adopting {name}");
+                config.write_device(entry, device);
+                report.adopted.push(name);
+            }
+            None => report.unknown.push(name),
+        }
+    }
+
+    // loop devices that are attached to our files but that no device reads from
+    let files: Vec<&OsString> = config
+        .entries()
+        .map(|(_, entry)| entry)
+        .chain(&report.dropped)
+        .flat_map(|entry| {
+            let layers = entry.layers.iter().map(|layer| &layer.image_file);
+            let overlay = entry.overlay.as_ref().map(|overlay| &overlay.file);
+            [&entry.image_file].into_iter().chain(layers).chain(overlay)
+        })
+        .collect();
+    report.leftover = kernel::loop_devices()
+        .into_iter()
+        .filter(|device| device.holders.is_empty())
+        .filter(|device| {
+            files.contains(&&device.backing_file)
+                || is_pattern_file(&device.backing_file, &state_folder)
+        })
+        .collect();
+
+    report
+}
+
+/// Finds the image and pattern file of a device from the loop devices it reads from
+fn identify(name: &str, state_folder: &Path) -> Option<ConfigEntry> {
+    let state = kernel::dm_state(name)?;
+    let mut image = None;
+    let mut pattern = None;
+
+    for slave in state.slaves {
+        let device = format!("/dev/{slave}");
+        let file = kernel::loop_backing_file(&device)?;
+        if is_pattern_file(&file, state_folder) {
+            pattern = Some(PatternLocation {
+                file,
+                mount_point: device,
+                devices: Vec::new(),
+            });
+        } else if image.is_none() {
+            image = Some((file, device));
+        } else {
+            // devices reading from several images cannot be identified, as the map files
+            // and order of precedence are not known
+            return None;
+        }
+    }
+
+    let (image_file, image_mount_point) = image?;
+    Some(ConfigEntry {
+        image_file,
+        block_size: kernel::logical_block_size(&image_mount_point),
+        image_mount_point,
+        dm_mount_point: name.to_string(),
+        pattern,
+        map_file: None,
+        mounted_at: None,
+        map_hash: None,
+        map_modified: None,
+        align: None,
+        options: None,
+        filesystem: None,
+        partitions: identify_partitions(name),
+        layers: Vec::new(),
+        overlay: identify_overlay(name),
+    })
+}
+
+/// Finds the writable snapshot stacked on a device from the loop device it stores writes on
+fn identify_overlay(name: &str) -> Option<Overlay> {
+    let overlay = format!("{name}-cow");
+    // the snapshot reads from the device and from the loop device of the overlay file
+    let (loop_device, file) = kernel::dm_state(&overlay)?
+        .slaves
+        .into_iter()
+        .find_map(|slave| {
+            let device = format!("/dev/{slave}");
+            kernel::loop_backing_file(&device).map(|file| (device, file))
+        })?;
+    Some(Overlay {
+        name: overlay,
+        file,
+        loop_device,
+    })
+}
+
+/// Finds the partition devices stacked on a device from their tables
+fn identify_partitions(name: &str) -> Vec<PartitionDevice> {
+    let prefix = format!("{name}p");
+    let mut partitions: Vec<PartitionDevice> = kernel::dm_devices()
+        .into_iter()
+        .filter_map(|(child, _)| {
+            let number = child.strip_prefix(&prefix)?.parse().ok()?;
+            // a single line like: 0 {size} linear {major:minor} {start}
+            let table = kernel::dm_table(&child)?;
+            let fields: Vec<&str> = table.split_whitespace().collect();
+            match fields[..] {
+                ["0", size, "linear", _, start] => Some(PartitionDevice {
+                    number,
+                    start: start.parse::<u128>().ok()? * 512,
+                    size: size.parse::<u128>().ok()? * 512,
+                    name: child,
+                }),
+                _ => None,
+            }
+        })
+        .collect();
+    partitions.sort_by_key(|partition| partition.number);
+    partitions
+}
+
+/// Checks if a file is a pattern file created when mounting
+pub fn is_pattern_file(file: &OsString, state_folder: &Path) -> bool {
+    let file = Path::new(file);
+    file.parent() == Some(state_folder)
+        && file
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with(DEVICE_NAME))
+        && file
+            .extension()
+            .is_some_and(|extension| extension == "pattern")
+}
+
+/// Checks the configuration against the kernel, saving the changes and printing any problems
+pub fn doctor() {
+    let mut config = Config::lock_config();
+    let report = reconcile(&mut config);
+    config.commit();
+    report.print();
+}
@@ -8,7 +8,7 @@ use std::{env, io::ErrorKind, process};
 #[repr(i32)]
 pub enum ExitCode {
     FileError = 1,
-    _ArgumentError = 2,
+    ArgumentError = 2,
     ConfigError = 3,
     MountError = 4,
     NonRoot = 5,
@@ -17,6 +17,9 @@ pub enum ExitCode {
     UnmountError = 8,
     SectorSizeError = 9,
     UnknownError = 10,
+    ImageSizeError = 11,
+    RefreshError = 12,
+    AffectedError = 13,
 }
 
 pub enum FileType {
@@ -30,12 +33,12 @@ pub enum Token {
     CurrentPos,
 }
 
-impl ToString for Token {
-    fn to_string(&self) -> String {
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Token::Pos => "position".to_string(),
-            Token::Size => "size".to_string(),
-            Token::CurrentPos => "current position".to_string(),
+            Token::Pos => write!(f, "position"),
+            Token::Size => write!(f, "size"),
+            Token::CurrentPos => write!(f, "current position"),
         }
     }
 }
@@ -53,16 +56,50 @@ pub const SET_CONFIG_ERROR: &str = "Unable to open or set configuration";
 pub const READ_CONFIG_ERROR: &str = "Unable to read configuration";
 pub const OOM_ERROR: &str = "Out of memory error!";
 pub const MOUNT_ERROR: &str = "Unable to mount image";
+pub const PARTITION_MOUNT_ERROR: &str = "Unable to create partition devices";
+pub const OVERLAY_MOUNT_ERROR: &str = "Unable to create writable overlay";
+pub const FILESYSTEM_MOUNT_ERROR: &str = "Unable to mount filesystem";
+pub const FILESYSTEM_UNMOUNT_ERROR: &str = "Unable to unmount filesystem";
 pub const NO_DEVICE_UNMOUNT_ERROR: &str = "Unmount error: Unable to find device";
 pub const UNMOUNT_ERROR: &str = "Unable to unmount device";
+pub const NO_DEVICE_REFRESH_ERROR: &str = "Refresh error: Unable to find device";
+pub const REFRESH_ERROR: &str = "Unable to refresh device";
+pub const WATCH_ERROR: &str = "Unable to watch map file";
+pub const NO_MOUNT_OPTIONS_ERROR: &str = "{device} was mounted by an older version of ddr-mount \
+that did not record its map file and options\nUnmount it and mount it again to refresh it";
+pub const NO_DEVICE_AFFECTED_ERROR: &str = "Unable to find device";
+pub const NOT_MOUNTED_ERROR: &str =
+    "Filesystem on the device is not mounted. Mount it or pass --mountpoint";
+pub const UNSUPPORTED_FILESYSTEM_ERROR: &str = "Only ext2, ext3 and ext4 filesystems are supported";
+pub const LAYER_SIZE_ERROR: &str =
+    "Every image must be the same size as the first, but this one is not:";
+pub const IMAGE_COUNT_ERROR: &str = "Every --image needs its own --map, given in the same order";
+pub const PARTITION_CHOICE_ERROR: &str =
+    "With --partitions, choose the partition whose filesystem is mounted with --partition";
+pub const NO_PARTITION_ERROR: &str = "No partition with this number was found:";
+pub const NO_LAYERS_ERROR: &str = "No images to create the table from";
+pub const LAYER_RANGE_ERROR: &str =
+    "Map file of image {layer} covers a different range than the first";
+pub const NO_PATTERN_ERROR: &str =
+    "Areas are filled with the pattern but there is no pattern device";
+pub const WRITE_MAP_ERROR: &str = "Unable to write map file";
+pub const EXPORT_ERROR: &str = "Unable to export image to";
+pub const EXPORT_OVERWRITE_ERROR: &str =
+    "The exported image and its map file must not overwrite the input:";
 pub const FILE_NOT_FOUND_ERROR: &str = "Unable to find";
-pub const SECTOR_SIZE_ERROR: &str = "Sector size is not a multiple of 512";
+pub const SECTOR_SIZE_ERROR: &str = "Sector size must be a non-zero multiple of 512";
+pub const WINDOW_ERROR: &str = "Offset and size must be multiples of the sector size and \
+fall within the map file and domain";
+pub const IMAGE_SIZE_ERROR: &str =
+    "Map file covers {map} bytes but the image file is {image} bytes\n\
+Use --clip to present only the image, treating any area the map file does not cover as untried";
 
 // Parser errors
 pub const CONTIGUOUS_ERROR: &str = "Position {pos} does not match size of previous line {size}\n\
 Disk map is not contiguous or is overlapping";
 pub const POSITION_SECTOR_ERROR: &str = "Position does not match sector size";
 pub const SIZE_SECTOR_ERROR: &str = "Size does not match sector size";
+pub const END_OVERFLOW_ERROR: &str = "Position and size overflow";
 pub const UNKNOWN_MAP_STATUS_ERROR: &str = "Unknown status character";
 pub const NO_POSITION_ERROR: &str = "No position found";
 pub const NO_SIZE_ERROR: &str = "No size found";
@@ -75,6 +112,9 @@ pub const NO_CURRENT_POSITION_ERROR: &str = "Current position in status line is
 pub const NO_CURRENT_STATUS_ERROR: &str = "Current status is missing from the status line";
 pub const UNKNOWN_CURRENT_STATUS_ERROR: &str = "Invalid status in status line";
 pub const UNKNOWN_CURRRENT_PHASE_ERROR: &str = "Invalid phase in status line";
+pub const ABORT_PARSE_ERROR: &str = "Could not parse map file due to {count} previous {errors}";
+pub const MAX_ERRORS_REACHED_ERROR: &str =
+    "Stopped parsing map file after {count} errors (error limit reached)";
 pub const CURRENT_PHASE_LESS_THAN_ONE_ERROR: &str =
     "Invalid phase in status line: phase must be 1 or greater";
 
@@ -130,6 +170,27 @@ pub fn root_error() {
     }
 }
 
+pub fn write_map_error(error: std::io::Error, filename: String) -> ! {
+    print_error(format!("{WRITE_MAP_ERROR} {filename}: {error}"));
+    process::exit(ExitCode::FileError as i32);
+}
+
+pub fn export_error(error: std::io::Error, output: &std::ffi::OsStr) -> ! {
+    print_error(format!(
+        "{EXPORT_ERROR} {}: {error}",
+        output.to_string_lossy()
+    ));
+    process::exit(ExitCode::FileError as i32);
+}
+
+pub fn export_overwrite_error(input: &std::ffi::OsStr) -> ! {
+    print_error(format!(
+        "{EXPORT_OVERWRITE_ERROR} {}",
+        input.to_string_lossy()
+    ));
+    process::exit(ExitCode::ArgumentError as i32);
+}
+
 pub fn check_io_error(error: std::io::Error, filename: String, filetype: FileType) -> ! {
     match error.kind() {
         ErrorKind::NotFound => {
@@ -143,6 +204,35 @@ pub fn check_io_error(error: std::io::Error, filename: String, filetype: FileTyp
     }
 }
 
+pub fn image_size_error(map: u128, image: u64) -> ! {
+    print_error(
+        IMAGE_SIZE_ERROR
+            .replace("{map}", &map.to_string())
+            .replace("{image}", &image.to_string()),
+    );
+    process::exit(ExitCode::ImageSizeError as i32);
+}
+
+pub fn layer_size_error(image: &std::ffi::OsStr) -> ! {
+    print_error(format!("{LAYER_SIZE_ERROR} {}", image.to_string_lossy()));
+    process::exit(ExitCode::ImageSizeError as i32);
+}
+
+pub fn image_count_error() -> ! {
+    print_error(IMAGE_COUNT_ERROR);
+    process::exit(ExitCode::ArgumentError as i32);
+}
+
+pub fn partition_choice_error() -> ! {
+    print_error(PARTITION_CHOICE_ERROR);
+    process::exit(ExitCode::ArgumentError as i32);
+}
+
+pub fn window_error() -> ! {
+    print_error(WINDOW_ERROR);
+    process::exit(ExitCode::ArgumentError as i32);
+}
+
 pub fn mount_error() -> ! {
     print_error(MOUNT_ERROR);
     process::exit(ExitCode::MountError as i32);
@@ -158,7 +248,100 @@ pub fn unmount_error() -> ! {
     process::exit(ExitCode::UnmountError as i32);
 }
 
-pub fn mount_error_clean(device: &str) -> ! {
-    let _ = crate::unmount::unmount_image(device.to_string(), ImageError::HideError);
+pub fn no_refresh_device(device: String) -> ! {
+    print_error(format!("{NO_DEVICE_REFRESH_ERROR} {device}"));
+    process::exit(ExitCode::RefreshError as i32);
+}
+
+pub fn no_mount_options_error(device: &str) -> ! {
+    print_error(NO_MOUNT_OPTIONS_ERROR.replace("{device}", device));
+    process::exit(ExitCode::RefreshError as i32);
+}
+
+pub fn no_affected_device(device: String) -> ! {
+    print_error(format!("{NO_DEVICE_AFFECTED_ERROR} {device}"));
+    process::exit(ExitCode::AffectedError as i32);
+}
+
+pub fn affected_error(error: &str) -> ! {
+    print_error(error);
+    process::exit(ExitCode::AffectedError as i32);
+}
+
+pub fn refresh_error() -> ! {
+    print_error(REFRESH_ERROR);
+    process::exit(ExitCode::RefreshError as i32);
+}
+
+pub fn watch_error() -> ! {
+    print_error(WATCH_ERROR);
+    process::exit(ExitCode::RefreshError as i32);
+}
+
+pub fn mount_error_clean(devices: &[&str]) -> ! {
+    for device in devices {
+        detach(device);
+    }
+    mount_error();
+}
+
+/// Removes the device mapper devices and detaches the loop devices after a failed mount
+pub fn mount_error_remove(dm_devices: &[&str], devices: &[&str]) -> ! {
+    remove_devices(dm_devices, devices);
     mount_error();
 }
+
+/// Detaches a loop device left by a failed mount, removing the file behind it if it is
+/// a pattern file, as nothing else would
+fn detach(device: &str) {
+    let backing_file = crate::kernel::loop_backing_file(device);
+    let _ = crate::unmount::unmount_image(device.to_string(), ImageError::HideError);
+    if let Some(file) = backing_file {
+        if crate::doctor::is_pattern_file(&file, &crate::config::state_folder()) {
+            let _ = std::fs::remove_file(file);
+        }
+    }
+}
+
+/// Removes the devices mapper devices, in order, and then the loop devices
+fn remove_devices(dm_devices: &[&str], devices: &[&str]) {
+    for device in dm_devices {
+        let _ = crate::kernel::dmsetup(&["remove", device], None);
+    }
+    for device in devices {
+        detach(device);
+    }
+}
+
+/// Removes the devices after the filesystem on them failed to mount
+pub fn filesystem_mount_error(dm_devices: &[&str], devices: &[&str]) -> ! {
+    remove_devices(dm_devices, devices);
+    print_error(FILESYSTEM_MOUNT_ERROR);
+    process::exit(ExitCode::MountError as i32);
+}
+
+/// Removes the devices after the partition chosen with --partition was not found
+pub fn no_partition_error(number: u32, dm_devices: &[&str], devices: &[&str]) -> ! {
+    remove_devices(dm_devices, devices);
+    print_error(format!("{NO_PARTITION_ERROR} {number}"));
+    process::exit(ExitCode::MountError as i32);
+}
+
+/// Removes the devices after the writable overlay could not be created
+pub fn overlay_error(dm_devices: &[&str], devices: &[&str]) -> ! {
+    remove_devices(dm_devices, devices);
+    print_error(OVERLAY_MOUNT_ERROR);
+    process::exit(ExitCode::MountError as i32);
+}
+
+/// Removes the devices after a partition device could not be created
+pub fn partition_mount_error(dm_devices: &[&str], devices: &[&str]) -> ! {
+    remove_devices(dm_devices, devices);
+    print_error(PARTITION_MOUNT_ERROR);
+    process::exit(ExitCode::MountError as i32);
+}
+
+pub fn filesystem_unmount_error() -> ! {
+    print_error(FILESYSTEM_UNMOUNT_ERROR);
+    process::exit(ExitCode::UnmountError as i32);
+}
@@ -0,0 +1,157 @@
+//! Copies the readable part of an image into a new image file, for tools that cannot use
+//! block devices. Areas that were not rescued become holes, or are filled with the pattern
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::Error as IOError;
+use std::os::unix::fs::{FileExt, MetadataExt};
+
+use crate::error::{self, FileType};
+use crate::mapfile::{BlockStatus, Mapfile};
+use crate::parser::ParseOptions;
+use crate::prepare::prepare_map;
+use crate::table::{pattern_sector, MountOptions};
+use log::{error, info};
+
+/// Bytes read and written at a time
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How areas that were not rescued are written to the exported image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fill {
+    /// Left as holes, which read as zeros and take no space
+    Zero,
+    /// Filled with the repeating marker, starting again at every sector
+    Pattern,
+}
+
+/// Bytes of the exported image that were copied from the image or filled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportSummary {
+    /// Finished areas copied from the image
+    pub copied: u128,
+    /// Areas that were not rescued
+    pub filled: u128,
+}
+
+/// Copies the finished blocks of the map file from image into output, which must be empty.
+/// The first block is written at the start of output, so a map file from [`Mapfile::slice`]
+/// exports only that part of the image. Chunks of zeros are skipped rather than written,
+/// so output stays sparse
+pub fn export_image(
+    image: &File,
+    output: &File,
+    mapfile: &Mapfile,
+    fill: Fill,
+    marker: &str,
+) -> Result<ExportSummary, IOError> {
+    let base = mapfile.blocks.first().map_or(0, |block| block.pos);
+    output.set_len((mapfile.end() - base) as u64)?;
+
+    let sector = pattern_sector(marker);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut summary = ExportSummary::default();
+
+    for block in &mapfile.blocks {
+        let mut pos = block.pos;
+        while pos < block.end() {
+            let length = (block.end() - pos).min(CHUNK_SIZE as u128) as usize;
+            let chunk = &mut buffer[..length];
+            let target = (pos - base) as u64;
+
+            match (block.status, fill) {
+                (BlockStatus::Finished, _) => {
+                    image.read_exact_at(chunk, pos as u64)?;
+                    if chunk.iter().any(|byte| *byte != 0) {
+                        output.write_all_at(chunk, target)?;
+                    }
+                }
+                (_, Fill::Zero) => {}
+                (_, Fill::Pattern) => {
+                    for (offset, byte) in chunk.iter_mut().enumerate() {
+                        *byte = sector[(target as usize + offset) % sector.len()];
+                    }
+                    output.write_all_at(chunk, target)?;
+                }
+            }
+            pos += length as u128;
+        }
+
+        if block.status == BlockStatus::Finished {
+            summary.copied += block.size;
+        } else {
+            summary.filled += block.size;
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Checks if both paths are the same file, so that exporting does not overwrite its own input
+fn same_file(a: &OsString, b: &OsString) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Exports the image as a new image file along with a map file for it. The map file is
+/// fitted the same way as when mounting, and written to output_map or next to output
+pub fn export(
+    image: OsString,
+    map: OsString,
+    output: OsString,
+    output_map: Option<OsString>,
+    fill: Fill,
+    options: ParseOptions,
+    mount_options: MountOptions,
+) {
+    info!(
+        "export image: {:?}, map: {:?}, output: {:?}",
+        image, map, output
+    );
+    let output_map = output_map.unwrap_or_else(|| {
+        let mut output_map = output.clone();
+        output_map.push(".map");
+        output_map
+    });
+    for input in [&image, &map] {
+        if same_file(input, &output) || same_file(input, &output_map) {
+            error::export_overwrite_error(input);
+        }
+    }
+
+    let (mapfile, _) = prepare_map(&image, &map, &options, &mount_options);
+
+    let image_file = File::open(&image).unwrap_or_else(|e| {
+        error::check_io_error(e, image.to_string_lossy().to_string(), FileType::ImageFile)
+    });
+    let summary = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&output)
+        .and_then(|output| {
+            export_image(&image_file, &output, &mapfile, fill, &mount_options.marker)
+        })
+        .unwrap_or_else(|e| {
+            error!("Unable to export to {:?}: {:?}", output, e);
+            error::export_error(e, &output)
+        });
+
+    fs::write(&output_map, mapfile.rebase().to_string())
+        .unwrap_or_else(|e| error::write_map_error(e, output_map.to_string_lossy().to_string()));
+
+    println!(
+        "{} exported to {}, with {} bytes copied and {} bytes {}",
+        image.to_string_lossy(),
+        output.to_string_lossy(),
+        summary.copied,
+        summary.filled,
+        match fill {
+            Fill::Zero => "left as holes",
+            Fill::Pattern => "filled with the pattern",
+        }
+    );
+    println!("Map file written to {}", output_map.to_string_lossy());
+}
@@ -0,0 +1,120 @@
+use std::ffi::OsString;
+use std::process::Command;
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+/// Filesystem to mount on the device once it is created
+#[derive(Debug, Clone)]
+pub struct FilesystemOptions {
+    pub mount_point: OsString,
+    /// Type passed to mount, detected with blkid if not set
+    pub fstype: Option<String>,
+    /// Options passed to mount, chosen by [`default_options`] if not set and the device is read-only
+    pub options: Option<String>,
+    /// Number of the partition whose filesystem is mounted, the whole device if not set
+    pub partition: Option<u32>,
+}
+
+/// Filesystem mounted on a device
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Filesystem {
+    pub mount_point: OsString,
+    /// None if the type could not be detected and was left to mount
+    pub fstype: Option<String>,
+    pub options: String,
+}
+
+/// Options that mount the filesystem read-only. Journaling filesystems also need to skip
+/// replaying their journal, as that writes to the device even when mounted read-only
+pub fn default_options(fstype: Option<&str>) -> String {
+    match fstype {
+        Some("ext3") | Some("ext4") => "ro,noload",
+        Some("xfs") => "ro,norecovery",
+        Some("btrfs") => "ro,rescue=nologreplay",
+        _ => "ro",
+    }
+    .to_string()
+}
+
+/// Adds ro to the options if it is not there, as the device cannot be written to
+fn read_only(options: &str) -> String {
+    if options.split(',').any(|option| option == "ro") {
+        options.to_string()
+    } else if options.is_empty() {
+        "ro".to_string()
+    } else {
+        format!("ro,{options}")
+    }
+}
+
+/// Gets the type of the filesystem on a device with ```blkid -o value -s TYPE {device}```
+pub fn detect_fstype(device: &str) -> Option<String> {
+    let output = Command::new("blkid")
+        .args(["-o", "value", "-s", "TYPE", device])
+        .output()
+        .map_err(|e| error!("Unable to run blkid: {:?}", e))
+        .ok()?;
+
+    let fstype = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    if !output.status.success() || fstype.is_empty() {
+        info!("blkid could not detect the filesystem on {device}");
+        return None;
+    }
+    Some(fstype)
+}
+
+/// Mounts the filesystem on a device with ```mount -t {type} -o {options} {device} {mount point}```.
+/// It is mounted read-only unless the device is writable
+pub(crate) fn mount_filesystem(
+    device: &str,
+    filesystem: FilesystemOptions,
+    writable: bool,
+) -> Result<Filesystem, ()> {
+    let fstype = filesystem.fstype.or_else(|| detect_fstype(device));
+    let options = match filesystem.options {
+        Some(options) if writable => options,
+        None if writable => "rw".to_string(),
+        options => read_only(&options.unwrap_or_else(|| default_options(fstype.as_deref()))),
+    };
+
+    let mut command = Command::new("mount");
+    if let Some(fstype) = &fstype {
+        command.args(["-t", fstype]);
+    }
+    command
+        .args(["-o", &options, device])
+        .arg(&filesystem.mount_point);
+    info!("{:?}", command);
+
+    let output = command
+        .output()
+        .map_err(|e| error!("Unable to run mount: {:?}", e))?;
+    if !output.status.success() {
+        error!("mount reported an error");
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        return Err(());
+    }
+
+    Ok(Filesystem {
+        mount_point: filesystem.mount_point,
+        fstype,
+        options,
+    })
+}
+
+/// Unmounts the filesystem with ```umount {mount point}```
+pub(crate) fn unmount_filesystem(filesystem: &Filesystem) -> Result<(), ()> {
+    info!("umount {:?}", filesystem.mount_point);
+    let output = Command::new("umount")
+        .arg(&filesystem.mount_point)
+        .output()
+        .map_err(|e| error!("Unable to run umount: {:?}", e))?;
+
+    if !output.status.success() {
+        error!("umount reported an error");
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+        return Err(());
+    }
+    Ok(())
+}
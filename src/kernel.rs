@@ -0,0 +1,172 @@
+use log::{error, info};
+use serde::Serialize;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::{ffi::OsString, fs, os::unix::prelude::*, path::Path};
+
+const SYS_BLOCK: &str = "/sys/block";
+
+/// State of a device mapper device as reported by the kernel in sysfs
+#[derive(Serialize, Debug, Clone)]
+pub struct DmState {
+    /// Kernel name of the device, like dm-0
+    pub kernel_name: String,
+    pub suspended: bool,
+    /// Devices stacked on top, like partitions or other device mapper devices
+    pub holders: Vec<String>,
+    /// Devices it reads from, like loop0
+    pub slaves: Vec<String>,
+}
+
+/// A loop device that is attached to a file
+#[derive(Serialize, Debug, Clone)]
+pub struct LoopDevice {
+    /// Path of the device, like /dev/loop0
+    pub device: String,
+    pub backing_file: OsString,
+    /// Devices reading from the loop device, like dm-0
+    pub holders: Vec<String>,
+}
+
+/// Reads a sysfs attribute without its trailing newline
+fn read_attribute(path: impl AsRef<Path>) -> Option<Vec<u8>> {
+    let mut contents = fs::read(path).ok()?;
+    if contents.last() == Some(&b'\n') {
+        contents.pop();
+    }
+    Some(contents)
+}
+
+/// Gets the names of every device mapper device, along with their kernel names
+pub fn dm_devices() -> Vec<(String, String)> {
+    let entries = match fs::read_dir(SYS_BLOCK) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let kernel_name = entry.file_name().into_string().ok()?;
+            let name = read_attribute(entry.path().join("dm/name"))?;
+            Some((String::from_utf8(name).ok()?, kernel_name))
+        })
+        .collect()
+}
+
+/// Gets the state of a device mapper device by name, or None if it does not exist
+pub fn dm_state(name: &str) -> Option<DmState> {
+    let (_, kernel_name) = dm_devices().into_iter().find(|(n, _)| n == name)?;
+    let path = Path::new(SYS_BLOCK).join(&kernel_name);
+
+    let suspended = read_attribute(path.join("dm/suspended")).as_deref() == Some(b"1");
+
+    Some(DmState {
+        kernel_name,
+        suspended,
+        holders: list_names(path.join("holders")),
+        slaves: list_names(path.join("slaves")),
+    })
+}
+
+/// Gets the names of the entries in a sysfs folder, like holders or slaves
+fn list_names(path: impl AsRef<Path>) -> Vec<String> {
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Gets every loop device that is attached to a file
+pub fn loop_devices() -> Vec<LoopDevice> {
+    let entries = match fs::read_dir(SYS_BLOCK) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let backing_file = read_attribute(entry.path().join("loop/backing_file"))?;
+            Some(LoopDevice {
+                device: format!("/dev/{name}"),
+                backing_file: OsString::from_vec(backing_file),
+                holders: list_names(entry.path().join("holders")),
+            })
+        })
+        .collect()
+}
+
+/// Gets the logical block size of a device, like /dev/loop0
+pub fn logical_block_size(device: &str) -> Option<u32> {
+    let name = Path::new(device).file_name()?;
+    let path = Path::new(SYS_BLOCK)
+        .join(name)
+        .join("queue/logical_block_size");
+    String::from_utf8(read_attribute(path)?).ok()?.parse().ok()
+}
+
+/// Gets the file a loop device (like /dev/loop0) is attached to, or None if it is not attached
+pub fn loop_backing_file(device: &str) -> Option<OsString> {
+    let name = Path::new(device).file_name()?;
+    let path = Path::new(SYS_BLOCK).join(name).join("loop/backing_file");
+    read_attribute(path).map(OsString::from_vec)
+}
+
+/// Gets the table of a device mapper device with ```dmsetup table {name}```
+pub fn dm_table(name: &str) -> Option<String> {
+    let output = Command::new("dmsetup")
+        .args(["table", name])
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Runs dmsetup, passing the table in stdin if there is one. Errors are logged
+pub fn dmsetup(args: &[&str], device_mapper: Option<&str>) -> Result<(), ()> {
+    info!(
+        "dmsetup{}",
+        args.iter()
+            .fold("".to_string(), |carry, item| format!("{carry} {item}"))
+    );
+
+    let mut dm_process = Command::new("dmsetup")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| error!("Unable to run dmsetup: {:?}", e))?;
+    let mut stdin = dm_process
+        .stdin
+        .take()
+        .ok_or_else(|| error!("Unable to take stdin of dmsetup"))?;
+    if let Some(device_mapper) = device_mapper {
+        stdin
+            .write_all(device_mapper.as_bytes())
+            .map_err(|e| error!("Unable to write to stdin of dmsetup: {:?}", e))?;
+    }
+    drop(stdin);
+
+    let status = dm_process.wait_with_output().map_err(|e| {
+        error!(
+            "Unable to get status of dmsetup after writing stdin: {:?}",
+            e
+        )
+    })?;
+
+    if !status.status.success() {
+        error!("Dmsetup reported an error");
+        eprintln!("{}", String::from_utf8_lossy(&status.stderr));
+        return Err(());
+    }
+    Ok(())
+}
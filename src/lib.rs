@@ -1,8 +1,50 @@
-#![allow(dead_code)]
-mod config;
-mod error;
-mod parser;
-mod unmount;
+mod align;
+pub mod combine;
+mod kernel;
+mod mapfile;
+pub mod partitions;
+mod prepare;
+mod table;
 
+// Commands run by the ddr-mount binary
+#[doc(hidden)]
+pub mod affected;
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod doctor;
+#[doc(hidden)]
+pub mod error;
+#[doc(hidden)]
+pub mod export;
+#[doc(hidden)]
+pub mod filesystem;
+#[doc(hidden)]
+pub mod mount;
+#[doc(hidden)]
+pub mod parser;
+#[doc(hidden)]
+pub mod refresh;
+#[doc(hidden)]
+pub mod stats;
+#[doc(hidden)]
+pub mod unmount;
+#[doc(hidden)]
+pub mod watch;
+
+pub use align::{align, check_block_size, AlignPolicy, SectorSizeError};
+#[doc(hidden)]
+pub use config::{parse_config, ConfigEntry};
+pub use export::{export_image, ExportSummary, Fill};
+pub use mapfile::{Block, BlockStatus, CurrentStatus, Mapfile, StatusLine};
 #[doc(hidden)]
 pub use parser::parse_map_string;
+pub use parser::{parse, parse_first, parse_with_options, Diagnostics, ParseError, ParseOptions};
+pub use stats::{BadExtents, Run, SizeClass, Stats, StatusCount, StatusCounts};
+#[doc(hidden)]
+pub use table::MountOptions;
+pub use table::{
+    create_composite_table, create_table, pattern_sector, pattern_stack_levels, pattern_stack_size,
+    pattern_stack_table, Layer, PatternDevice, Sources, StatusPolicy, TableError, Target,
+    PATTERN_FAN_OUT, PATTERN_SIZE,
+};
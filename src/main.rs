@@ -1,15 +1,14 @@
 mod arguments;
-mod config;
-mod error;
-mod mount;
-mod parser;
-mod unmount;
 
 use arguments::*;
-use config::list_devices;
-use mount::*;
+use ddr_mount::config::{self, list_devices, ListFormat};
+use ddr_mount::filesystem::FilesystemOptions;
+use ddr_mount::mount::*;
+use ddr_mount::{affected, combine, doctor, error, export, parser, refresh, stats, unmount, watch};
+use ddr_mount::{AlignPolicy, BlockStatus, Mapfile, MountOptions, StatusPolicy, Target};
 
 use log::info;
+use std::time::Duration;
 use sudo::escalate_if_needed;
 use update_informer::{registry, Check};
 
@@ -30,26 +29,230 @@ fn main() {
         println!("New version is available: {version}");
     }
 
+    if let Some(state_dir) = args.state_dir {
+        config::set_state_folder(state_dir.into());
+    }
+
     match args.command {
         Commands::Mount {
             image,
             map,
             block_size,
+            max_errors,
+            align,
+            bad_as,
+            pattern,
+            treat,
+            clip,
+            offset,
+            size,
+            domain_map,
+            partitions,
+            mountpoint,
+            partition,
+            fstype,
+            options,
+            cow,
+            follow,
+            debounce,
+        } => {
+            if image.len() != map.len() {
+                error::image_count_error();
+            }
+            if partitions && mountpoint.is_some() && partition.is_none() {
+                error::partition_choice_error();
+            }
+            ensure_root();
+            let device = mount(
+                image.into_iter().zip(map).collect(),
+                parser::ParseOptions {
+                    max_errors,
+                    block_size,
+                    align: align_policy(align),
+                    check_alignment: true,
+                },
+                MountOptions {
+                    policy: status_policy(bad_as, &treat),
+                    marker: pattern,
+                    clip,
+                    offset,
+                    size,
+                    domain_map,
+                    partitions,
+                },
+                mountpoint.map(|mount_point| FilesystemOptions {
+                    mount_point,
+                    fstype,
+                    options,
+                    partition,
+                }),
+                cow,
+            );
+            if follow {
+                watch::watch(device, Duration::from_millis(debounce));
+            }
+        }
+        Commands::Export {
+            image,
+            map,
+            output,
+            output_map,
+            block_size,
+            max_errors,
+            align,
+            fill,
+            pattern,
+            clip,
+            offset,
+            size,
+            domain_map,
+        } => export::export(
+            image,
+            map,
+            output,
+            output_map,
+            match fill {
+                Fill::Zero => export::Fill::Zero,
+                Fill::Pattern => export::Fill::Pattern,
+            },
+            parser::ParseOptions {
+                max_errors,
+                block_size,
+                align: align_policy(align),
+                check_alignment: true,
+            },
+            MountOptions {
+                policy: StatusPolicy::default(),
+                marker: pattern,
+                clip,
+                offset,
+                size,
+                domain_map,
+                partitions: false,
+            },
+        ),
+        Commands::Unmount {
+            device,
+            keep_cow,
+            discard_cow,
         } => {
             ensure_root();
-            mount(image, map, block_size);
+            let keep_overlay = match (keep_cow, discard_cow) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            };
+            unmount::unmount(device, keep_overlay)
+        }
+        Commands::Refresh { device } => {
+            ensure_root();
+            refresh::refresh(device);
         }
-        Commands::Unmount { device } => {
+        Commands::Watch { device, debounce } => {
             ensure_root();
-            unmount::unmount(device)
+            watch::watch(device, Duration::from_millis(debounce));
+        }
+        Commands::Affected { device, mountpoint } => {
+            ensure_root();
+            affected::affected(device, mountpoint);
         }
         Commands::UnmountAll => {
             ensure_root();
             unmount::unmount_all();
         }
-        Commands::List => {
-            list_devices();
+        Commands::Doctor => {
+            ensure_root();
+            doctor::doctor();
+        }
+        Commands::Stats { map, format } => {
+            // alignment only matters when mounting, so any map file ddrescue writes is accepted
+            let mapfile = parser::parse_map(
+                &map,
+                &parser::ParseOptions {
+                    check_alignment: false,
+                    ..Default::default()
+                },
+            );
+            let stats = stats::Stats::new(&mapfile);
+            match format {
+                StatsFormat::Human => println!("{stats}"),
+                StatsFormat::Json => println!("{}", serde_json::to_string_pretty(&stats).unwrap()),
+            }
         }
+        Commands::Mapfile { operation } => {
+            let (mapfile, output) = match operation {
+                MapfileOperation::Merge { maps, output } => {
+                    (combine::merge(&parse_maps(&maps)), output)
+                }
+                MapfileOperation::Or { maps, output } => (combine::or(&parse_maps(&maps)), output),
+                MapfileOperation::And { maps, output } => {
+                    (combine::and(&parse_maps(&maps)), output)
+                }
+                MapfileOperation::Diff { old, new, output } => {
+                    let maps = parse_maps(&[old, new]);
+                    (Some(combine::diff(&maps[0], &maps[1])), output)
+                }
+            };
+            // clap requires at least two map files
+            let mapfile = mapfile.unwrap();
+
+            match output {
+                Some(output) => std::fs::write(&output, mapfile.to_string()).unwrap_or_else(|e| {
+                    error::write_map_error(e, output.to_string_lossy().to_string())
+                }),
+                None => print!("{mapfile}"),
+            }
+        }
+        Commands::List { format } => {
+            list_devices(match format {
+                Format::Human => ListFormat::Human,
+                Format::Json => ListFormat::Json,
+                Format::Tsv => ListFormat::Tsv,
+            });
+        }
+    }
+}
+
+fn align_policy(align: Align) -> AlignPolicy {
+    match align {
+        Align::Strict => AlignPolicy::Strict,
+        Align::ExpandBad => AlignPolicy::ExpandBad,
+        Align::ShrinkBad => AlignPolicy::ShrinkBad,
+    }
+}
+
+/// Parses map files for combining, exiting on any error. Alignment only matters when mounting,
+/// so any map file ddrescue writes is accepted
+fn parse_maps(maps: &[std::ffi::OsString]) -> Vec<Mapfile> {
+    let options = parser::ParseOptions {
+        check_alignment: false,
+        ..Default::default()
+    };
+    maps.iter()
+        .map(|map| parser::parse_map(map, &options))
+        .collect()
+}
+
+/// Builds the status policy from `--bad-as` and then the `--treat` arguments,
+/// with later arguments taking priority
+fn status_policy(bad_as: Treat, treat: &[(char, Treat)]) -> StatusPolicy {
+    let mut policy = StatusPolicy::default();
+    for status in ['?', '*', '/', '-'] {
+        policy.set(BlockStatus::from_char(status).unwrap(), target(bad_as));
+    }
+    for (status, treat) in treat {
+        let status = BlockStatus::from_char(*status).unwrap();
+        policy.set(status, target(*treat));
+    }
+    policy
+}
+
+fn target(treat: Treat) -> Target {
+    match treat {
+        Treat::Error => Target::Error,
+        Treat::Linear => Target::Linear,
+        Treat::Zero => Target::Zero,
+        Treat::Pattern => Target::Pattern,
     }
 }
 
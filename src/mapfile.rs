@@ -0,0 +1,361 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+
+/// Status of a block of data in the map file
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockStatus {
+    /// `?` - block not yet tried
+    NonTried,
+    /// `*` - failed block, not yet trimmed
+    NonTrimmed,
+    /// `/` - failed block, not yet scraped
+    NonScraped,
+    /// `-` - failed block, bad sector(s)
+    BadSector,
+    /// `+` - finished block
+    Finished,
+}
+
+impl BlockStatus {
+    pub fn from_char(status: char) -> Option<BlockStatus> {
+        match status {
+            '?' => Some(BlockStatus::NonTried),
+            '*' => Some(BlockStatus::NonTrimmed),
+            '/' => Some(BlockStatus::NonScraped),
+            '-' => Some(BlockStatus::BadSector),
+            '+' => Some(BlockStatus::Finished),
+            _ => None,
+        }
+    }
+
+    pub fn as_char(&self) -> char {
+        match self {
+            BlockStatus::NonTried => '?',
+            BlockStatus::NonTrimmed => '*',
+            BlockStatus::NonScraped => '/',
+            BlockStatus::BadSector => '-',
+            BlockStatus::Finished => '+',
+        }
+    }
+}
+
+/// Phase ddrescue was in when the map file was written
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CurrentStatus {
+    /// `?` - copying non-tried blocks
+    Copying,
+    /// `*` - trimming non-trimmed blocks
+    Trimming,
+    /// `/` - scraping non-scraped blocks
+    Scraping,
+    /// `-` - retrying bad sectors
+    Retrying,
+    /// `F` - filling specified blocks
+    Filling,
+    /// `G` - generating approximate map file
+    Generating,
+    /// `+` - finished
+    Finished,
+}
+
+impl CurrentStatus {
+    pub fn from_char(status: char) -> Option<CurrentStatus> {
+        match status {
+            '?' => Some(CurrentStatus::Copying),
+            '*' => Some(CurrentStatus::Trimming),
+            '/' => Some(CurrentStatus::Scraping),
+            '-' => Some(CurrentStatus::Retrying),
+            'F' => Some(CurrentStatus::Filling),
+            'G' => Some(CurrentStatus::Generating),
+            '+' => Some(CurrentStatus::Finished),
+            _ => None,
+        }
+    }
+
+    pub fn as_char(&self) -> char {
+        match self {
+            CurrentStatus::Copying => '?',
+            CurrentStatus::Trimming => '*',
+            CurrentStatus::Scraping => '/',
+            CurrentStatus::Retrying => '-',
+            CurrentStatus::Filling => 'F',
+            CurrentStatus::Generating => 'G',
+            CurrentStatus::Finished => '+',
+        }
+    }
+
+    /// Message ddrescue writes in the comment above the status line
+    fn message(&self) -> &'static str {
+        match self {
+            CurrentStatus::Copying => "Copying non-tried blocks...",
+            CurrentStatus::Trimming => "Trimming failed blocks...",
+            CurrentStatus::Scraping => "Scraping failed blocks...",
+            CurrentStatus::Retrying => "Retrying bad sectors...",
+            CurrentStatus::Filling => "Filling specified blocks...",
+            CurrentStatus::Generating => "Generating approximate mapfile...",
+            CurrentStatus::Finished => "Finished",
+        }
+    }
+}
+
+/// First non-comment line of the map file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusLine {
+    pub current_pos: u128,
+    pub current_status: CurrentStatus,
+    /// Older ddrescue versions and HDDSuperClone do not write the pass
+    pub current_pass: Option<u8>,
+}
+
+/// A contiguous range of the rescue domain in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Block {
+    pub pos: u128,
+    pub size: u128,
+    pub status: BlockStatus,
+}
+
+impl Block {
+    /// Position of the first byte after the block
+    pub fn end(&self) -> u128 {
+        self.pos + self.size
+    }
+}
+
+/// A parsed ddrescue map file
+/// Structure of map file can be found [here](https://www.gnu.org/software/ddrescue/manual/ddrescue_manual.html#Mapfile-structure)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapfile {
+    pub status: StatusLine,
+    pub blocks: Vec<Block>,
+}
+
+impl Mapfile {
+    /// Position of the first byte after the last block
+    pub fn end(&self) -> u128 {
+        self.blocks.last().map(Block::end).unwrap_or(0)
+    }
+
+    /// Makes the map file cover exactly size bytes. Blocks past size are cut off,
+    /// and if the map file is shorter, the rest is added as non-tried
+    pub fn clip(&mut self, size: u128) {
+        self.blocks.retain(|block| block.pos < size);
+        if let Some(last) = self.blocks.last_mut() {
+            last.size = last.size.min(size - last.pos);
+        }
+
+        let end = self.end();
+        if end < size {
+            self.blocks.push(Block {
+                pos: end,
+                size: size - end,
+                status: BlockStatus::NonTried,
+            });
+        }
+    }
+
+    /// Keeps only the part of the map file within range, cutting the blocks at either end.
+    /// Positions are not changed, so the first block starts at the start of range
+    pub fn slice(&self, range: Range<u128>) -> Mapfile {
+        let blocks = self
+            .blocks
+            .iter()
+            .filter(|block| block.end() > range.start && block.pos < range.end)
+            .map(|block| {
+                let pos = block.pos.max(range.start);
+                Block {
+                    pos,
+                    size: block.end().min(range.end) - pos,
+                    status: block.status,
+                }
+            })
+            .collect();
+
+        Mapfile {
+            status: self.status,
+            blocks,
+        }
+    }
+
+    /// Moves the blocks so the first one starts at 0, as when that part of the disk
+    /// is copied out on its own. The current position moves with them
+    pub fn rebase(&self) -> Mapfile {
+        let base = self.blocks.first().map_or(0, |block| block.pos);
+        Mapfile {
+            status: StatusLine {
+                current_pos: self.status.current_pos.saturating_sub(base),
+                ..self.status
+            },
+            blocks: self
+                .blocks
+                .iter()
+                .map(|block| Block {
+                    pos: block.pos - base,
+                    ..*block
+                })
+                .collect(),
+        }
+    }
+
+    /// Range from the start of the first finished block to the end of the last one
+    pub fn finished_span(&self) -> Option<Range<u128>> {
+        let mut finished = self
+            .blocks
+            .iter()
+            .filter(|block| block.status == BlockStatus::Finished && block.size > 0);
+        let first = finished.next()?;
+        let last = finished.next_back().unwrap_or(first);
+        Some(first.pos..last.end())
+    }
+
+    /// Number of bytes in finished blocks
+    pub fn finished_bytes(&self) -> u128 {
+        self.finished_blocks().map(|block| block.size).sum()
+    }
+
+    /// Number of bytes that are finished in both map files
+    pub fn finished_overlap(&self, other: &Mapfile) -> u128 {
+        let mut other = other.finished_blocks().peekable();
+        let mut overlap = 0;
+
+        for block in self.finished_blocks() {
+            while let Some(next) = other.peek() {
+                let start = block.pos.max(next.pos);
+                let end = block.end().min(next.end());
+                overlap += end.saturating_sub(start);
+                if next.end() > block.end() {
+                    break;
+                }
+                other.next();
+            }
+        }
+        overlap
+    }
+
+    /// Number of bytes that are finished in this map file but were not in an older one,
+    /// such as areas recovered by another ddrescue pass
+    pub fn recovered_since(&self, old: &Mapfile) -> u128 {
+        self.finished_bytes() - self.finished_overlap(old)
+    }
+
+    /// Number of bytes within range that have the status
+    pub fn status_bytes(&self, range: Range<u128>, status: BlockStatus) -> u128 {
+        self.slice(range)
+            .blocks
+            .iter()
+            .filter(|block| block.status == status)
+            .map(|block| block.size)
+            .sum()
+    }
+
+    /// Checks if every byte within range is finished. Bytes past the end are not
+    pub fn is_finished(&self, range: Range<u128>) -> bool {
+        range.end <= self.end()
+            && self.status_bytes(range.clone(), BlockStatus::Finished) == range.end - range.start
+    }
+
+    fn finished_blocks(&self) -> impl Iterator<Item = &Block> {
+        self.blocks
+            .iter()
+            .filter(|block| block.status == BlockStatus::Finished)
+    }
+
+    /// Marks every area outside the finished blocks of a domain map file as non-tried,
+    /// as ddrescue never reads outside of the domain
+    pub fn restrict(&self, domain: &Mapfile) -> Mapfile {
+        let domain: Vec<Range<u128>> = domain
+            .blocks
+            .iter()
+            .filter(|block| block.status == BlockStatus::Finished)
+            .map(|block| block.pos..block.end())
+            .collect();
+
+        let mut blocks: Vec<Block> = Vec::with_capacity(self.blocks.len());
+        let mut i = 0;
+
+        for block in &self.blocks {
+            let mut pos = block.pos;
+            while pos < block.end() {
+                while i < domain.len() && domain[i].end <= pos {
+                    i += 1;
+                }
+
+                let (end, status) = match domain.get(i) {
+                    Some(range) if range.start <= pos => (range.end, block.status),
+                    Some(range) => (range.start, BlockStatus::NonTried),
+                    None => (block.end(), BlockStatus::NonTried),
+                };
+                let end = end.min(block.end());
+
+                match blocks.last_mut() {
+                    Some(last) if last.status == status => last.size += end - pos,
+                    _ => blocks.push(Block {
+                        pos,
+                        size: end - pos,
+                        status,
+                    }),
+                }
+                pos = end;
+            }
+        }
+
+        Mapfile {
+            status: self.status,
+            blocks,
+        }
+    }
+
+    /// Writes the map file in the format ddrescue writes, so that ddrescue can resume from it
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        write!(writer, "{self}")
+    }
+}
+
+impl fmt::Display for Mapfile {
+    /// Formats the map file in the format ddrescue writes
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "# Mapfile. Created by ddr-mount version {}",
+            env!("CARGO_PKG_VERSION")
+        )?;
+        writeln!(f, "# {}", self.status.current_status.message())?;
+
+        match self.status.current_pass {
+            Some(pass) => {
+                writeln!(f, "# current_pos  current_status  current_pass")?;
+                writeln!(
+                    f,
+                    "0x{:08X}     {}               {pass}",
+                    self.status.current_pos,
+                    self.status.current_status.as_char()
+                )?;
+            }
+            None => {
+                writeln!(f, "# current_pos  current_status")?;
+                writeln!(
+                    f,
+                    "0x{:08X}     {}",
+                    self.status.current_pos,
+                    self.status.current_status.as_char()
+                )?;
+            }
+        }
+
+        writeln!(f, "#      pos        size  status")?;
+        for block in &self.blocks {
+            writeln!(
+                f,
+                "0x{:08X}  0x{:08X}  {}",
+                block.pos,
+                block.size,
+                block.status.as_char()
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -1,45 +1,388 @@
 use std::{
     ffi::OsString,
-    io::{ErrorKind, Write as IoWrite},
+    fs::{self, File},
+    io::{ErrorKind, Seek, SeekFrom},
     process::{self, Command},
 };
 
-use crate::config::{self, DM_LOCATION};
+use crate::config::{
+    self, ConfigEntry, ImageLayer, Overlay, PartitionDevice, PatternLocation, DM_LOCATION,
+};
+use crate::doctor;
 use crate::error::{self, FileType};
-use crate::parser::parse_map;
+use crate::filesystem::{mount_filesystem, FilesystemOptions};
+use crate::kernel::{self, dmsetup};
+use crate::parser::ParseOptions;
+use crate::partitions::{read_partitions, PartitionError};
+use crate::prepare::{prepare_maps, unix_time};
+use crate::table::{
+    create_composite_table, pattern_sector, pattern_stack_levels, pattern_stack_table, Layer,
+    MountOptions, Sources, Target, PATTERN_SIZE,
+};
 use log::{error, info};
 use std::io::Error as IOError;
-use std::path::Path;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-/// Mounts the image
-pub fn mount(image: OsString, map: OsString, block_size: u32) {
-    info!(
-        "mount image: {:?}, map: {:?}, block size: {block_size}",
-        image, map
+/// Mounts the images of a disk along with their map files, returning the name of the new device.
+/// With several images, each area is read from the first image that rescued it
+pub fn mount(
+    images: Vec<(OsString, OsString)>,
+    options: ParseOptions,
+    mount_options: MountOptions,
+    filesystem: Option<FilesystemOptions>,
+    cow: Option<OsString>,
+) -> String {
+    let block_size = options.block_size;
+    for (image, map) in &images {
+        info!(
+            "mount image: {:?}, map: {:?}, block size: {block_size}",
+            image, map
+        );
+    }
+
+    let images: Vec<(OsString, OsString)> = images
+        .into_iter()
+        .map(|(image, map)| (absolute_image_path(image), map))
+        .collect();
+
+    info!("Full path of images: {:?}", images);
+
+    // parse the map files before attaching anything so errors do not leave loop devices behind
+    let (mapfiles, snapshots) = prepare_maps(&images, &options, &mount_options);
+    let record = mount_options.clone();
+    let MountOptions {
+        policy,
+        marker,
+        partitions,
+        ..
+    } = mount_options;
+
+    // hold the lock from choosing the device number until it is recorded
+    let mut config = config::Config::lock_config();
+    doctor::reconcile(&mut config).print_changes();
+    let entry = config.next_entry();
+    let device_name = format!("{}{}", config::DEVICE_NAME, entry);
+
+    // mount the images
+    let mut image_mount_paths: Vec<String> = Vec::new();
+    for (image, _) in &images {
+        let attached: Vec<&str> = image_mount_paths.iter().map(String::as_str).collect();
+        let image_mount_path = losetup_mount(image, block_size, &attached);
+        info!("Image mounted at {image_mount_path}");
+        image_mount_paths.push(image_mount_path);
+    }
+    let mut sources = Sources::new(&image_mount_paths[0]);
+    let size = mapfiles[0].end() - mapfiles[0].blocks.first().map_or(0, |block| block.pos);
+
+    // mount the pattern file if any bad areas are filled with the pattern
+    let pattern = if policy.uses(Target::Pattern) {
+        let mut attached: Vec<&str> = image_mount_paths.iter().map(String::as_str).collect();
+        let mut file = config::config_folder();
+        file.push(format!("{device_name}.pattern"));
+        create_pattern_file(&file, &marker).unwrap_or_else(|e| {
+            error!("Unable to create pattern file {:?}: {:?}", file, e);
+            let _ = fs::remove_file(&file);
+            error::mount_error_clean(&attached)
+        });
+        let file = file.into_os_string();
+        let pattern_mount_path = losetup(&file, block_size, true).unwrap_or_else(|_| {
+            let _ = fs::remove_file(&file);
+            error::mount_error_clean(&attached)
+        });
+        info!("Pattern mounted at {pattern_mount_path}");
+        attached.push(&pattern_mount_path);
+        let pattern = pattern_stack(
+            &device_name,
+            PatternLocation {
+                file,
+                mount_point: pattern_mount_path.clone(),
+                devices: Vec::new(),
+            },
+            size,
+            &attached,
+        );
+        sources.pattern = Some(pattern.device());
+        Some(pattern)
+    } else {
+        None
+    };
+
+    // mount the device mapper over image mounts, creating error I/O range using map files
+    let loop_devices: Vec<&str> = image_mount_paths
+        .iter()
+        .chain(pattern.as_ref().map(|pattern| &pattern.mount_point))
+        .map(String::as_str)
+        .collect();
+    // devices stacked on the pattern file are removed after the device that reads from them
+    let pattern_devices: Vec<&str> = pattern
+        .iter()
+        .flat_map(|pattern| pattern.devices.iter().rev())
+        .map(String::as_str)
+        .collect();
+    let layers: Vec<Layer> = mapfiles
+        .iter()
+        .zip(&image_mount_paths)
+        .map(|(mapfile, device)| Layer { mapfile, device })
+        .collect();
+    let table =
+        create_composite_table(&layers, sources.pattern.as_ref(), &policy).unwrap_or_else(|e| {
+            error!("{e}");
+            error::mount_error_remove(&pattern_devices, &loop_devices)
+        });
+    dm_mount(&device_name, &table, &pattern_devices, &loop_devices);
+
+    let mut dm_devices: Vec<&str> = vec![&device_name];
+    dm_devices.extend(&pattern_devices);
+
+    // stack the writable snapshot on the device, so partitions and the filesystem are writable
+    let overlay = cow.map(|file| {
+        overlay_mount(
+            &device_name,
+            file,
+            size,
+            block_size,
+            &dm_devices,
+            &loop_devices,
+        )
+    });
+    let mut loop_devices = loop_devices;
+    if let Some(overlay) = &overlay {
+        loop_devices.push(&overlay.loop_device);
+        dm_devices.insert(0, &overlay.name);
+    }
+    let top = dm_devices[0];
+
+    let device_path = format!("{DM_LOCATION}{top}");
+    let partitions = if partitions {
+        partition_mount(&device_name, &dm_devices, &loop_devices)
+    } else {
+        Vec::new()
+    };
+
+    let dm_devices: Vec<&str> = partitions
+        .iter()
+        .map(|p| p.name.as_str())
+        .chain(dm_devices.iter().copied())
+        .collect();
+    let filesystem = filesystem.map(|filesystem| {
+        let filesystem_device = match filesystem.partition {
+            Some(number) => match partitions.iter().find(|p| p.number == number) {
+                Some(partition) => format!("{DM_LOCATION}{}", partition.name),
+                None => error::no_partition_error(number, &dm_devices, &loop_devices),
+            },
+            None => device_path.clone(),
+        };
+        let mounted = mount_filesystem(&filesystem_device, filesystem, overlay.is_some())
+            .unwrap_or_else(|_| error::filesystem_mount_error(&dm_devices, &loop_devices));
+        (mounted, filesystem_device)
+    });
+
+    let mounted_at = unix_time(SystemTime::now());
+    let names: Vec<String> = images
+        .iter()
+        .map(|(image, _)| image.to_string_lossy().to_string())
+        .collect();
+    let mut images = images.into_iter().zip(image_mount_paths).zip(snapshots);
+    let (((image, map), image_mount_path), snapshot) = images.next().unwrap();
+    let layers = images
+        .map(
+            |(((image_file, map), image_mount_point), snapshot)| ImageLayer {
+                image_file,
+                image_mount_point,
+                map_hash: Some(snapshot.hash),
+                map_modified: snapshot.modified,
+                map_file: absolute_map_path(map),
+            },
+        )
+        .collect();
+
+    config.write_device(
+        entry,
+        ConfigEntry {
+            image_file: image,
+            image_mount_point: image_mount_path,
+            dm_mount_point: device_name.clone(),
+            pattern,
+            block_size: Some(block_size),
+            map_file: Some(absolute_map_path(map)),
+            mounted_at,
+            map_hash: Some(snapshot.hash),
+            map_modified: snapshot.modified,
+            align: Some(options.align),
+            options: Some(record),
+            filesystem: filesystem
+                .as_ref()
+                .map(|(filesystem, _)| filesystem.clone()),
+            partitions: partitions.clone(),
+            layers,
+            overlay: overlay.clone(),
+        },
     );
+    config.commit();
 
-    if block_size % 512 != 0 {
-        error!("Sector size not a multiple of 512, {}", block_size % 512);
-        error::sector_error();
+    println!(
+        "{} is mounted at {DM_LOCATION}{device_name}",
+        names.join(" + ")
+    );
+    if let Some(overlay) = overlay {
+        println!(
+            "{device_path} is writable, with writes kept in {}",
+            overlay.file.to_string_lossy()
+        );
+    }
+    for partition in partitions {
+        println!(
+            "Partition {} is mounted at {DM_LOCATION}{}",
+            partition.number, partition.name
+        );
     }
+    if let Some((filesystem, filesystem_device)) = filesystem {
+        println!(
+            "Filesystem on {filesystem_device} is mounted at {}",
+            filesystem.mount_point.to_string_lossy()
+        );
+    }
+    device_name
+}
+
+/// Creates a device for every partition of the device, reading the partition table from
+/// the top of dm_devices, which are the devices stacked for it from the top down.
+/// The partition devices read from it, so they return I/O errors in the same places
+fn partition_mount(
+    device_name: &str,
+    dm_devices: &[&str],
+    loop_devices: &[&str],
+) -> Vec<PartitionDevice> {
+    let device_path = format!("{DM_LOCATION}{}", dm_devices[0]);
+    // the partition table uses the logical sector size of the disk. The loop device of the image
+    // is attached with the sector size given when mounting, which may be larger than the
+    // logical sector size of a disk with 512-byte logical sectors, so 512 is tried after it
+    let logical = loop_devices
+        .first()
+        .and_then(|device| kernel::logical_block_size(device))
+        .unwrap_or(512);
+    let table = File::open(&device_path).and_then(|mut file| {
+        let size = file.seek(SeekFrom::End(0))?;
+        let read = |pos: u64, len: usize| {
+            let mut buffer = vec![0; len];
+            file.read_exact_at(&mut buffer, pos).ok().map(|_| buffer)
+        };
+        let table = match read_partitions(read, size, logical) {
+            Err(PartitionError::NoTable) if logical != 512 => {
+                info!("No partition table with {logical}-byte sectors, trying 512-byte sectors");
+                read_partitions(read, size, 512)
+            }
+            table => table,
+        };
+        Ok((table, size))
+    });
 
-    let image = absolute_image_path(image);
+    let (table, size) = match table {
+        Ok((Ok(table), size)) => (table, size as u128),
+        Ok((Err(e), _)) => {
+            println!("{e}, so no partition devices were created");
+            return Vec::new();
+        }
+        Err(e) => {
+            error!("Unable to open {device_path}: {:?}", e);
+            error::partition_mount_error(dm_devices, loop_devices)
+        }
+    };
+    info!("Found {:?} partition table", table.kind);
 
-    info!("Full path of image: {:?}", image);
+    let mut partitions: Vec<PartitionDevice> = Vec::new();
+    for partition in table.partitions {
+        if partition.size == 0 || partition.end() > size || partition.start % 512 != 0 {
+            println!(
+                "Partition {} does not fit in the image, so no device was created for it",
+                partition.number
+            );
+            continue;
+        }
 
-    // mount the image
-    let image_mount_path = losetup_mount(&image, block_size);
-    info!("Image mounted at {image_mount_path}");
-    let image_path = OsString::from(&image_mount_path);
+        let name = format!("{device_name}p{}", partition.number);
+        let table = format!(
+            "0 {} linear {device_path} {}\n",
+            partition.size / 512,
+            partition.start / 512
+        );
+        if dmsetup(&["create", &name], Some(&table)).is_err() {
+            let dm_devices: Vec<&str> = partitions
+                .iter()
+                .map(|p| p.name.as_str())
+                .chain(dm_devices.iter().copied())
+                .collect();
+            error::partition_mount_error(&dm_devices, loop_devices);
+        }
+        partitions.push(PartitionDevice {
+            name,
+            number: partition.number,
+            start: partition.start,
+            size: partition.size,
+        });
+    }
+    partitions
+}
 
-    // mount the device mapper over image mount, creating error I/O range using map file
-    let (entry, device_name) = dm_mount(&map, &image_path);
+/// Stacks a persistent dm snapshot on the device, storing writes in the file so that the
+/// images are never changed. The file is created sparse if it does not exist, and an existing
+/// overlay for the same device is carried on with
+fn overlay_mount(
+    device_name: &str,
+    file: OsString,
+    size: u128,
+    block_size: u32,
+    dm_devices: &[&str],
+    loop_devices: &[&str],
+) -> Overlay {
+    let name = format!("{device_name}-cow");
+    // the snapshot stores its metadata alongside the chunks it copies
+    let capacity = (size + size / 32 + 1024 * 1024) as u64;
+    let created = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&file)
+        .and_then(|overlay| {
+            if overlay.metadata()?.len() < capacity {
+                overlay.set_len(capacity)?;
+            }
+            Ok(())
+        });
+    if let Err(e) = created {
+        error!("Unable to create overlay file {:?}: {:?}", file, e);
+        error::overlay_error(dm_devices, loop_devices);
+    }
+    let file = fs::canonicalize(&file).map_or(file, PathBuf::into_os_string);
 
-    let mut config = config::Config::read_config();
-    config.write_device(image.clone(), entry, image_mount_path, device_name.clone());
+    let loop_device = losetup(&file, block_size, false).unwrap_or_else(|_| {
+        error::overlay_error(dm_devices, loop_devices);
+    });
+    info!("Overlay mounted at {loop_device}");
 
-    let x = image.to_string_lossy();
-    println!("{x} is mounted at {DM_LOCATION}{device_name}");
+    let chunk = (block_size / 512).max(8);
+    let table = format!(
+        "0 {} snapshot {DM_LOCATION}{device_name} {loop_device} P {chunk}\n",
+        size / 512
+    );
+    if dmsetup(&["create", &name], Some(&table)).is_err() {
+        let mut loop_devices = loop_devices.to_vec();
+        loop_devices.push(&loop_device);
+        error::overlay_error(dm_devices, &loop_devices);
+    }
+
+    Overlay {
+        name,
+        file,
+        loop_device,
+    }
+}
+
+/// Gets the absolute path of the map file, keeping the path as given if it cannot be found
+fn absolute_map_path(map: OsString) -> OsString {
+    fs::canonicalize(&map).map_or(map, PathBuf::into_os_string)
 }
 
 /// Gets the absolute path of the image
@@ -64,31 +407,29 @@ fn absolute_image_path(path: OsString) -> OsString {
 
 /// Mounts the image using losetup. First, it finds an empty loop device using losetup -f
 /// Then it mounts by doing:
-/// ```losetup {loopdev} {path to image file} -b {sector size} -r```
-/// Finally returns the loopdev path
-fn losetup_mount(image: &OsString, block_size: u32) -> String {
+/// ```losetup {loopdev} {path to image file} -r -b {sector size}```
+/// Finally returns the loopdev path. Loop devices already attached are detached on failure
+fn losetup_mount(image: &OsString, block_size: u32, attached: &[&str]) -> String {
+    losetup(image, block_size, true).unwrap_or_else(|_| error::mount_error_clean(attached))
+}
+
+/// Attaches the file to the next free loop device, returning its path. Errors are logged
+fn losetup(file: &OsString, block_size: u32, read_only: bool) -> Result<String, ()> {
     let losetup_next_loop_device = Command::new("losetup")
         .args(["-f"])
         .output()
-        .unwrap_or_else(|e| {
-            error!("Unable to run losetup to find nextloop: {:?}", e);
-            error::mount_error()
-        });
+        .map_err(|e| error!("Unable to run losetup to find nextloop: {:?}", e))?;
     let image_mount_path = String::from_utf8(losetup_next_loop_device.stdout)
-        .unwrap_or_else(|e| {
-            error!("Unable to get next loop device from losetup: {:?}", e);
-            error::mount_error()
-        })
+        .map_err(|e| error!("Unable to get next loop device from losetup: {:?}", e))?
         .trim_matches('\n')
         .to_string();
 
-    let args = [
-        &OsString::from(&image_mount_path),
-        image,
-        &OsString::from("-r"),
-        &OsString::from("-b"),
-        &OsString::from(block_size.to_string()),
-    ];
+    let mut args = vec![OsString::from(&image_mount_path), file.clone()];
+    if read_only {
+        args.push(OsString::from("-r"));
+    }
+    args.push(OsString::from("-b"));
+    args.push(OsString::from(block_size.to_string()));
 
     info!(
         "losetup{}",
@@ -102,69 +443,49 @@ fn losetup_mount(image: &OsString, block_size: u32) -> String {
         .args(args)
         .stdin(process::Stdio::null())
         .output()
-        .unwrap_or_else(|e| {
-            error!("losetup run error {:?}", e);
-            error::mount_error()
-        });
+        .map_err(|e| error!("losetup run error {:?}", e))?;
 
     if !image_mount_status.status.success() {
         error!("Losetup reported an error");
-        eprintln!("{}", String::from_utf8(image_mount_status.stderr).unwrap());
-        error::mount_error();
+        eprintln!("{}", String::from_utf8_lossy(&image_mount_status.stderr));
+        return Err(());
     }
-    image_mount_path
+    Ok(image_mount_path)
 }
 
-/// Mounts the image using the parse map with the following commmand
-/// ```dmsetup create {device name}``` and passes parse map in stdin
-fn dm_mount(map: &OsString, image_path: &OsString) -> (u32, String) {
-    let entry = config::get_next_devices();
-    let image_path = image_path.to_str().unwrap();
-
-    let device_name = format!("{}{}", config::DEVICE_NAME, entry);
-    let device_mapper = &parse_map(map, image_path);
-
-    let args = ["create", &device_name];
-    info!(
-        "dmsetup{}",
-        args.iter()
-            .fold("".to_string(), |carry, item| format!("{carry} {item}"))
-    );
-
-    let mut dm_mount_process = Command::new("dmsetup")
-        .args(args)
-        .stdin(process::Stdio::piped())
-        .spawn()
-        .unwrap_or_else(|e| {
-            error!("Unable to run dmsetup: {:?}", e);
-            error::mount_error_clean(image_path)
-        });
-    dm_mount_process
-        .stdin
-        .take()
-        .unwrap_or_else(|| {
-            error!("Unable to take stdin of dmsetup");
-            error::mount_error_clean(image_path)
-        })
-        .write_all(device_mapper.as_bytes())
-        .unwrap_or_else(|e| {
-            error!("Unable to write to stdin of dmsetup: {:?}", e);
-            error::mount_error_clean(image_path)
-        });
-
-    let status = dm_mount_process.wait_with_output().unwrap_or_else(|e| {
-        error!(
-            "Unable to get status of dmsetup after writing stdin: {:?}",
-            e
-        );
-        error::mount_error_clean(image_path)
-    });
+/// Writes a pattern file filled with the marker, to be used for pattern targets
+fn create_pattern_file(path: &Path, marker: &str) -> Result<(), IOError> {
+    let sector = pattern_sector(marker);
+    let contents = sector.repeat((PATTERN_SIZE / 512) as usize);
+    fs::write(path, contents)
+}
 
-    if !status.status.success() {
-        error!("Dmsetup reported an error");
-        eprintln!("{}", String::from_utf8(status.stderr).unwrap());
-        error::mount_error_clean(image_path);
+/// Mounts the image using the parse map with the following commmand
+/// ```dmsetup create {device name}``` and passes parse map in stdin.
+/// Loop devices are detached if dmsetup fails
+fn dm_mount(device_name: &str, device_mapper: &str, dm_devices: &[&str], loop_devices: &[&str]) {
+    if dmsetup(&["create", device_name], Some(device_mapper)).is_err() {
+        error::mount_error_remove(dm_devices, loop_devices);
     }
+}
 
-    (entry, device_name)
+/// Stacks devices on the loop device of the pattern file until the top one covers size bytes,
+/// each made of copies of the one below, so that every pattern extent fits in one line
+fn pattern_stack(
+    device_name: &str,
+    mut pattern: PatternLocation,
+    size: u128,
+    loop_devices: &[&str],
+) -> PatternLocation {
+    for level in 1..=pattern_stack_levels(size) {
+        let name = format!("{device_name}-pattern{level}");
+        let table = pattern_stack_table(&pattern.device());
+        if dmsetup(&["create", &name], Some(&table)).is_err() {
+            let created: Vec<&str> = pattern.devices.iter().rev().map(String::as_str).collect();
+            error::mount_error_remove(&created, loop_devices);
+        }
+        pattern.devices.push(name);
+    }
+    info!("Pattern stacked on {:?}", pattern.devices);
+    pattern
 }
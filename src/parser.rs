@@ -1,214 +1,439 @@
+use crate::align::{check_block_size, AlignPolicy};
 use crate::error::{self, Token};
+use crate::mapfile::{Block, BlockStatus, CurrentStatus, Mapfile, StatusLine};
+use crate::table::{create_table, Sources, StatusPolicy};
 use colored::Colorize;
-use std::{borrow::Cow, ffi::OsString, fmt::Write, fs};
+use std::{
+    ffi::OsString,
+    fmt::{self, Write},
+    fs,
+    ops::Range,
+};
 use unicode_width::UnicodeWidthStr;
 
-struct Number<'a> {
-    pos: (u128, &'a str),
-    size: (u128, &'a str),
+/// Error found while parsing a map file, pointing at the offending token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub filename: String,
+    /// Line number starting from 1, or 0 if the error is not tied to a line
+    pub line: usize,
+    /// Byte range of the offending token in the line
+    pub span: Range<usize>,
+    pub message: String,
+    text: String,
 }
 
+impl ParseError {
+    fn new(line: &Line, span: Range<usize>, message: impl Into<String>) -> ParseError {
+        ParseError {
+            filename: line.filename.clone(),
+            line: line.line_num,
+            span,
+            message: message.into(),
+            text: line.line.to_string(),
+        }
+    }
+
+    /// Error about the whole file rather than one of its lines
+    fn whole_file(filename: String, message: impl Into<String>) -> ParseError {
+        ParseError {
+            filename,
+            line: 0,
+            span: 0..0,
+            message: message.into(),
+            text: String::new(),
+        }
+    }
+
+    /// Renders the error in the same way cargo does
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        // Writing to a String only fails when out of memory
+        let _ = self.render_to(&mut output);
+        output
+    }
+
+    fn render_to(&self, f: &mut impl Write) -> fmt::Result {
+        let seperator = "|".blue().bold();
+
+        if self.line == 0 {
+            writeln!(f, "{} {}", "-->".blue().bold(), self.filename)?;
+            return write!(f, "{}", self.message.red().bold());
+        }
+
+        let padding = " ".repeat(self.line.to_string().len());
+
+        writeln!(f, "{padding} {} {}", "-->".blue().bold(), self.filename)?;
+        writeln!(f, " {padding} {seperator}")?;
+        writeln!(
+            f,
+            " {} {seperator} {}",
+            self.line.to_string().blue().bold(),
+            self.text
+        )?;
+        writeln!(
+            f,
+            " {padding} {seperator} {}{}",
+            " ".repeat(UnicodeWidthStr::width(&self.text[..self.span.start])),
+            // I considered checking for unicode whitespace to ignore in ^, but I want to consider that an error
+            // as ddrescue only considers ASCII whitespace valid
+            "^".repeat(UnicodeWidthStr::width(&self.text[self.span.clone()]))
+                .red()
+                .bold()
+        )?;
+        write!(f, "{}", self.message.red().bold())
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.render_to(f)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 struct Line<'a> {
-    filename: Cow<'a, str>,
+    filename: String,
     line_num: usize,
     line: &'a str,
 }
 
-/// Reads the map file and send it to parser
-pub fn parse_map(map_path: &OsString, device_name: &str) -> String {
-    let contents = fs::read_to_string(map_path.clone()).unwrap_or_else(|error| {
+impl Line<'_> {
+    /// Byte range of the token, searching from `from`
+    fn span(&self, from: usize, token: &str) -> Range<usize> {
+        let start = get_next(from, self.line, token);
+        start..start + token.len()
+    }
+
+    fn error(&self, span: Range<usize>, message: impl Into<String>) -> ParseError {
+        ParseError::new(self, span, message)
+    }
+}
+
+/// Options controlling how a map file is parsed
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// Stop parsing once this many errors have been found. 0 is the same as no limit
+    pub max_errors: Option<usize>,
+    /// Sector size of the disk that was imaged. Must be a multiple of 512
+    pub block_size: u32,
+    /// How extents that do not fall on sector boundaries are handled
+    pub align: AlignPolicy,
+    /// Check that extents fall on sector boundaries. Only needed for map files that are
+    /// mounted, so it can be turned off to accept any map file ddrescue writes
+    pub check_alignment: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            max_errors: None,
+            block_size: 512,
+            align: AlignPolicy::Strict,
+            check_alignment: true,
+        }
+    }
+}
+
+/// All errors found while parsing a map file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostics {
+    errors: Vec<ParseError>,
+    /// Parsing stopped early because `max_errors` was reached
+    truncated: bool,
+}
+
+impl Diagnostics {
+    pub fn errors(&self) -> &[ParseError] {
+        &self.errors
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Summary line printed after all the errors, similar to rustc
+    pub fn summary(&self) -> String {
+        let count = self.errors.len();
+        if self.truncated {
+            error::MAX_ERRORS_REACHED_ERROR.replace("{count}", &count.to_string())
+        } else {
+            error::ABORT_PARSE_ERROR
+                .replace("{count}", &count.to_string())
+                .replace("{errors}", if count == 1 { "error" } else { "errors" })
+        }
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for error in &self.errors {
+            writeln!(f, "{error}\n")?;
+        }
+        write!(f, "{}", self.summary().red().bold())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+/// Collects errors until the limit from [`ParseOptions`] is reached
+struct Collector {
+    errors: Vec<ParseError>,
+    max_errors: Option<usize>,
+    /// An error was dropped or lines were skipped because the limit was reached
+    truncated: bool,
+}
+
+impl Collector {
+    fn new(max_errors: Option<usize>) -> Collector {
+        Collector {
+            errors: Vec::new(),
+            max_errors: max_errors.filter(|max| *max > 0),
+            truncated: false,
+        }
+    }
+
+    fn push(&mut self, error: ParseError) {
+        if self.is_full() {
+            self.truncated = true;
+        } else {
+            self.errors.push(error);
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        matches!(self.max_errors, Some(max) if self.errors.len() >= max)
+    }
+}
+
+/// Reads the map file and send it to parser, printing any errors and exiting
+pub fn parse_map(map_path: &OsString, options: &ParseOptions) -> Mapfile {
+    parse_map_contents(map_path, &read_map(map_path), options)
+}
+
+/// Reads the map file, printing any error and exiting
+pub fn read_map(map_path: &OsString) -> String {
+    fs::read_to_string(map_path.clone()).unwrap_or_else(|error| {
         error::check_io_error(
             error,
             map_path.clone().into_string().unwrap_or_default(),
             crate::error::FileType::MapFile,
         )
-    });
+    })
+}
 
-    parse_map_string(map_path, &contents, device_name)
+/// Parses the contents of a map file that was already read, printing any errors and exiting
+pub fn parse_map_contents(map_path: &OsString, contents: &str, options: &ParseOptions) -> Mapfile {
+    parse_with_options(map_path, contents, options).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        error::parse_error(false)
+    })
 }
 
-/// Creates dmtable for error device
-fn create_error(contents: Number, line: &Line) -> String {
-    let Number {
-        pos: (pos, pos_string),
-        size: (size, size_string),
-    } = contents;
-    format!(
-        "{} {} error",
-        sector(pos, || report_error(
-            line,
-            line.line.find(pos_string).unwrap(),
-            pos_string,
-            error::POSITION_SECTOR_ERROR
-        )),
-        sector(size, || report_error(
-            line,
-            line.line.rfind(size_string).unwrap(),
-            size_string,
-            error::SIZE_SECTOR_ERROR
-        ))
+/// Parses ddrescue map file to dmsetup table, printing any error and exiting
+pub fn parse_map_string(filename: &OsString, contents: &str, device_name: &str) -> String {
+    let mapfile = parse(filename, contents).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        error::parse_error(false)
+    });
+
+    create_table(
+        &mapfile,
+        &Sources::new(device_name),
+        &StatusPolicy::default(),
     )
+    .unwrap_or_else(|e| {
+        error::print_error(e);
+        error::parse_error(false)
+    })
 }
 
-/// Creates dmtable for linear device
-fn create_linear(contents: Number, device: &str, line: &Line) -> String {
-    let Number {
-        pos: (pos, pos_string),
-        size: (size, size_string),
-    } = contents;
-    let pos_sector = sector(pos, || {
-        report_error(
-            line,
-            line.line.find(pos_string).unwrap(),
-            pos_string,
-            error::POSITION_SECTOR_ERROR,
-        )
-    });
-    let size_sector = sector(size, || {
-        report_error(
-            line,
-            line.line.rfind(size_string).unwrap(),
-            size_string,
-            error::SIZE_SECTOR_ERROR,
-        )
-    });
-    format!("{pos_sector} {size_sector} linear {device} {pos_sector}")
+/// Parses ddrescue map file with the default options
+pub fn parse(filename: &OsString, contents: &str) -> Result<Mapfile, Diagnostics> {
+    parse_with_options(filename, contents, &ParseOptions::default())
 }
 
-/// Parses ddrescue map file to dmsetup table
+/// Parses ddrescue map file with the default options, stopping at the first error
+pub fn parse_first(filename: &OsString, contents: &str) -> Result<Mapfile, ParseError> {
+    let options = ParseOptions {
+        max_errors: Some(1),
+        ..Default::default()
+    };
+    parse_with_options(filename, contents, &options).map_err(|diagnostics| {
+        diagnostics.errors.into_iter().next().unwrap_or_else(|| {
+            ParseError::whole_file(filename.to_string_lossy().to_string(), error::PARSE_ERROR)
+        })
+    })
+}
+
+/// Parses ddrescue map file, continuing past errors so that all of them are reported at once.
+/// Fails without reading the file if the sector size in options is invalid
 /// Structure of map file can be found [here](https://www.gnu.org/software/ddrescue/manual/ddrescue_manual.html#Mapfile-structure)
-pub fn parse_map_string(filename: &OsString, contents: &str, device_name: &str) -> String {
-    let mut output = String::new();
+pub fn parse_with_options(
+    filename: &OsString,
+    contents: &str,
+    options: &ParseOptions,
+) -> Result<Mapfile, Diagnostics> {
+    let filename = filename.to_string_lossy().to_string();
+    if let Err(e) = check_block_size(options.block_size) {
+        return Err(Diagnostics {
+            errors: vec![ParseError::whole_file(filename, e.to_string())],
+            truncated: false,
+        });
+    }
+    let mut errors = Collector::new(options.max_errors);
 
     let mut file_line = contents
         .lines()
         .enumerate()
-        .map(|s| (s.0, s.1.trim()))
+        .map(|s| (s.0 + 1, s.1.trim()))
         .filter(|s| !s.1.is_empty() && !s.1.contains('#'));
 
-    verify_status_line(&mut file_line, filename.to_string_lossy());
+    let status = parse_status_line(&mut file_line, &filename, &mut errors);
 
-    let mut prev_entry = 0;
+    let mut blocks = Vec::new();
+    // None when the end of the previous line could not be worked out
+    let mut prev_entry = Some(0);
+
+    for (line_num, line) in file_line {
+        if errors.is_full() {
+            errors.truncated = true;
+            break;
+        }
 
-    #[allow(clippy::redundant_closure)]
-    for (line_number, line) in file_line {
         let line = Line {
-            filename: filename.to_string_lossy(),
-            line_num: line_number,
+            filename: filename.clone(),
+            line_num,
             line,
         };
 
-        let mut map_line = line.line.split_ascii_whitespace();
-        let pos_string = map_line
-            .next()
-            .unwrap_or_else(|| {
-                report_error(&line, 0, line.line, error::NO_POSITION_ERROR);
-            })
-            .to_string();
-        let size_string = map_line
-            .next()
-            .unwrap_or_else(|| {
-                report_error(&line, 0, line.line, error::NO_SIZE_ERROR);
-            })
-            .to_string();
-        let status = map_line.next().unwrap_or_else(|| {
-            report_error(&line, 0, line.line, error::NO_STATUS_ERROR);
-        });
+        let (pos, size, status) = parse_block(&line, options, &mut errors);
 
-        let status = status.parse::<char>().unwrap_or_else(|_| {
-            report_error(
-                &line,
-                line.line.rfind(status).unwrap(),
-                status,
-                error::UNKNOWN_MAP_STATUS_ERROR,
-            )
-        });
+        // Check if sector is contiguous
+        if let (Some(pos), Some(prev)) = (pos, prev_entry) {
+            if pos != prev {
+                let pos_span = line.span(0, line.line.split_ascii_whitespace().next().unwrap());
+                if prev == 0 {
+                    errors.push(line.error(pos_span, error::START_NONZERO_ERROR));
+                } else {
+                    errors.push(
+                        line.error(
+                            pos_span,
+                            error::CONTIGUOUS_ERROR
+                                .replace("{pos}", &pos.to_string())
+                                .replace("{size}", &prev.to_string()),
+                        ),
+                    );
+                }
+            }
+        }
 
-        let pos_location = get_next(0, line.line, &pos_string);
-        let size_location = get_next(pos_location + pos_string.len(), line.line, &size_string);
-        let status_location = get_next(
-            size_location + size_string.len(),
-            line.line,
-            &status.to_string(),
-        );
-
-        let pos = convert_to_num(&pos_string, || {
-            report_error(
-                &line,
-                pos_location,
-                &pos_string,
-                &error::convert_error_string(Token::Pos),
-            )
-        });
+        // Resynchronise on this line so that one gap is only reported once
+        prev_entry = match pos.zip(size) {
+            Some((pos, size)) => {
+                let end = pos.checked_add(size);
+                if end.is_none() {
+                    let mut tokens = line.line.split_ascii_whitespace();
+                    let pos_span = line.span(0, tokens.next().unwrap());
+                    let size_span = line.span(pos_span.end, tokens.next().unwrap());
+                    errors.push(line.error(size_span, error::END_OVERFLOW_ERROR));
+                }
+                end
+            }
+            None => None,
+        };
 
-        let size = convert_to_num(&size_string, || {
-            report_error(
-                &line,
-                size_location,
-                &size_string,
-                &error::convert_error_string(Token::Size),
-            )
-        });
+        if let (Some(pos), Some(size), Some(status), Some(_)) = (pos, size, status, prev_entry) {
+            blocks.push(Block { pos, size, status });
+        }
+    }
 
-        let number = Number {
-            pos: (pos, &pos_string),
-            size: (size, &size_string),
-        };
+    match status {
+        Some(status) if errors.errors.is_empty() => Ok(Mapfile { status, blocks }),
+        _ => Err(Diagnostics {
+            truncated: errors.truncated,
+            errors: errors.errors,
+        }),
+    }
+}
 
-        match status {
-            '+' => error::handle_string_write(writeln!(
-                output,
-                "{}",
-                create_linear(number, device_name, &line)
-            )),
-            '?' | '*' | '/' | '-' => {
-                error::handle_string_write(writeln!(output, "{}", create_error(number, &line)))
+/// Parses a line of the map file, returning whichever fields are valid
+fn parse_block(
+    line: &Line,
+    options: &ParseOptions,
+    errors: &mut Collector,
+) -> (Option<u128>, Option<u128>, Option<BlockStatus>) {
+    let mut map_line = line.line.split_ascii_whitespace();
+    let whole_line = 0..line.line.len();
+
+    let (pos_string, size_string, status_string) =
+        match (map_line.next(), map_line.next(), map_line.next()) {
+            (Some(pos), Some(size), Some(status)) => (pos, size, status),
+            (None, _, _) => {
+                errors.push(line.error(whole_line, error::NO_POSITION_ERROR));
+                return (None, None, None);
             }
-            x => {
-                let x = &x.to_string();
-                report_error(&line, status_location, x, error::UNKNOWN_MAP_STATUS_ERROR);
+            (_, None, _) => {
+                errors.push(line.error(whole_line, error::NO_SIZE_ERROR));
+                return (None, None, None);
             }
+            (_, _, None) => {
+                errors.push(line.error(whole_line, error::NO_STATUS_ERROR));
+                return (None, None, None);
+            }
+        };
+
+    let pos_span = line.span(0, pos_string);
+    let size_span = line.span(pos_span.end, size_string);
+    let status_span = line.span(size_span.end, status_string);
+
+    let status = status_string
+        .parse::<char>()
+        .ok()
+        .and_then(BlockStatus::from_char);
+    if status.is_none() {
+        errors.push(line.error(status_span, error::UNKNOWN_MAP_STATUS_ERROR));
+    }
+
+    // Bad and untried extents must not split a sector of the original disk, otherwise
+    // the error device would return data the disk could not. Finished extents only
+    // need to be addressable by device mapper, which uses 512-byte sectors
+    let sector = match (options.check_alignment, options.align, status) {
+        (false, _, _) => None,
+        (true, AlignPolicy::Strict, Some(status)) if status != BlockStatus::Finished => {
+            Some(options.block_size as u128)
         }
+        (true, AlignPolicy::Strict, _) => Some(512),
+        // Extents are rounded to sector boundaries after parsing
+        _ => None,
+    };
+    let is_aligned = |value: u128| sector.is_none_or(|sector| value.is_multiple_of(sector));
 
-        // Check if sector is contiguous
-        if pos != prev_entry {
-            if prev_entry == 0 {
-                report_error(
-                    &line,
-                    line.line.find(&pos_string).unwrap(),
-                    &pos_string,
-                    error::START_NONZERO_ERROR,
-                );
-            } else {
-                report_error(
-                    &line,
-                    line.line.find(&pos_string).unwrap(),
-                    &pos_string,
-                    &error::CONTIGUOUS_ERROR
-                        .replace("{pos}", &pos.to_string())
-                        .replace("{size}", &prev_entry.to_string()),
-                );
-            }
-        } else {
-            prev_entry = pos + size;
+    let pos = convert_to_num(pos_string);
+    match pos {
+        None => errors.push(line.error(pos_span, error::convert_error_string(Token::Pos))),
+        Some(pos) if !is_aligned(pos) => {
+            errors.push(line.error(pos_span, error::POSITION_SECTOR_ERROR))
         }
+        Some(_) => (),
     }
 
-    output
-}
-/// Divides into 512-byte sectors
-fn sector(size: u128, error: impl FnOnce()) -> u128 {
-    if size % 512 != 0 {
-        error();
-        unreachable!()
-    } else {
-        size / 512
+    let size = convert_to_num(size_string);
+    match size {
+        None => errors.push(line.error(size_span, error::convert_error_string(Token::Size))),
+        Some(size) if !is_aligned(size) => {
+            errors.push(line.error(size_span, error::SIZE_SECTOR_ERROR))
+        }
+        Some(_) => (),
     }
+
+    (pos, size, status)
 }
 
 /// ddrescue expects pos and size to be based on C++ integer notation
 /// C++ notation allows either decimal, hex (beginning with 0x), or octal (beginning with 0)
-fn convert_to_num(num_string: &str, error: impl FnOnce()) -> u128 {
+fn convert_to_num(num_string: &str) -> Option<u128> {
     let mut num_string = num_string;
 
     let radix = if num_string.starts_with("0x") {
@@ -220,130 +445,87 @@ fn convert_to_num(num_string: &str, error: impl FnOnce()) -> u128 {
         10
     };
 
-    u128::from_str_radix(num_string, radix).unwrap_or_else(|_| {
-        error();
-        unreachable!()
-    })
-}
-
-/// Prints error in the same way cargo does
-fn report_error(line: &Line, parse_start: usize, token: &str, message: &str) -> ! {
-    let Line {
-        filename,
-        line_num,
-        line,
-    } = line;
-
-    let seperator = "|".blue().bold();
-
-    let padding = " ".repeat(line_num.to_string().len());
-
-    eprintln!("{padding} {} {filename}", "-->".blue().bold());
-    eprintln!(" {padding} {seperator}");
-    eprintln!(" {} {seperator} {line}", line_num.to_string().blue().bold());
-
-    eprintln!(
-        " {padding} {seperator} {}{}",
-        " ".repeat(parse_start),
-        // I considered checking for unicode whitespace to ignore in ^, but I want to consider that an error
-        // as ddrescue only considers ASCII whitespace valid
-        "^".repeat(UnicodeWidthStr::width(token)).red().bold()
-    );
-    error::print_error(message);
-    error::parse_error(false)
+    u128::from_str_radix(num_string, radix).ok()
 }
 
-fn verify_status_line<'a, T>(lines: &mut T, filename: Cow<'a, str>)
+fn parse_status_line<'a, T>(
+    lines: &mut T,
+    filename: &str,
+    errors: &mut Collector,
+) -> Option<StatusLine>
 where
     T: Iterator<Item = (usize, &'a str)>,
 {
-    let (line_number, line) = lines.next().unwrap_or_else(|| {
-        error::print_error(error::EMPTY_MAP_ERROR);
-        error::parse_error(false)
-    });
+    let Some((line_num, line)) = lines.next() else {
+        errors.push(ParseError::whole_file(
+            filename.to_string(),
+            error::EMPTY_MAP_ERROR,
+        ));
+        return None;
+    };
 
     let mut contents = line.split_ascii_whitespace();
 
-    let line_content = Line {
-        filename,
-        line_num: line_number,
+    let line = Line {
+        filename: filename.to_string(),
+        line_num,
         line,
     };
+    let whole_line = 0..line.line.len();
 
-    let current_pos = contents
-        .next()
-        .unwrap_or_else(|| report_error(&line_content, 0, line, error::NO_CURRENT_POSITION_ERROR));
-
-    let current_pos_location = get_next(0, line, current_pos);
-
-    convert_to_num(current_pos, || {
-        report_error(
-            &line_content,
-            current_pos_location,
-            current_pos,
-            &error::convert_error_string(Token::CurrentPos),
-        )
-    });
-
-    let current_status = contents
-        .next()
-        .unwrap_or_else(|| report_error(&line_content, 0, line, error::NO_CURRENT_STATUS_ERROR));
-
-    let current_status_location = get_next(
-        current_pos_location + current_pos.len(),
-        line,
-        current_status,
-    );
-
-    let current_status = current_status.parse::<char>().unwrap_or_else(|_| {
-        report_error(
-            &line_content,
-            current_status_location,
-            current_status,
-            error::UNKNOWN_CURRENT_STATUS_ERROR,
-        )
-    });
+    let Some(current_pos_string) = contents.next() else {
+        errors.push(line.error(whole_line, error::NO_CURRENT_POSITION_ERROR));
+        return None;
+    };
+    let current_pos_span = line.span(0, current_pos_string);
+
+    let current_pos = convert_to_num(current_pos_string);
+    if current_pos.is_none() {
+        errors.push(line.error(
+            current_pos_span.clone(),
+            error::convert_error_string(Token::CurrentPos),
+        ));
+    }
 
-    let current_status_location = get_next(
-        current_pos_location + current_pos.len(),
-        line,
-        &current_status.to_string(),
-    );
-
-    match current_status {
-        '?' | '*' | '/' | '-' | 'F' | 'G' | '+' => (),
-        x => report_error(
-            &line_content,
-            current_status_location,
-            &x.to_string(),
+    let Some(current_status_string) = contents.next() else {
+        errors.push(line.error(whole_line, error::NO_CURRENT_STATUS_ERROR));
+        return None;
+    };
+    let current_status_span = line.span(current_pos_span.end, current_status_string);
+
+    let current_status = current_status_string
+        .parse::<char>()
+        .ok()
+        .and_then(CurrentStatus::from_char);
+    if current_status.is_none() {
+        errors.push(line.error(
+            current_status_span.clone(),
             error::UNKNOWN_CURRENT_STATUS_ERROR,
-        ),
+        ));
     }
 
-    let current_pass = contents.next();
-
-    match current_pass {
-        Some(x) => {
-            let x_location = get_next(current_status_location + 1, line, x);
-            let current_status = x.parse::<u8>().unwrap_or_else(|_| {
-                report_error(
-                    &line_content,
-                    x_location,
-                    x,
-                    error::UNKNOWN_CURRRENT_PHASE_ERROR,
-                )
-            });
-            if current_status < 1 {
-                report_error(
-                    &line_content,
-                    x_location,
-                    x,
-                    error::CURRENT_PHASE_LESS_THAN_ONE_ERROR,
-                );
+    let current_pass = if let Some(x) = contents.next() {
+        let x_span = line.span(current_status_span.end, x);
+        match x.parse::<u8>() {
+            Ok(0) => {
+                errors.push(line.error(x_span, error::CURRENT_PHASE_LESS_THAN_ONE_ERROR));
+                return None;
+            }
+            Ok(current_pass) => Some(current_pass),
+            Err(_) => {
+                errors.push(line.error(x_span, error::UNKNOWN_CURRRENT_PHASE_ERROR));
+                return None;
             }
         }
-        None => (),
+    } else {
+        None
     };
+
+    Some(StatusLine {
+        current_pos: current_pos?,
+        current_status: current_status?,
+        current_pass,
+    })
 }
 
 fn get_next(prev: usize, line: &str, next: &str) -> usize {
@@ -0,0 +1,351 @@
+//! Reads MBR and GPT partition tables, either from a device or from an image file
+//! along with its map file, without needing root
+
+use std::fmt;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::mapfile::{BlockStatus, Mapfile};
+use log::info;
+use serde::Serialize;
+
+/// Partition type byte of an MBR entry protecting a GPT disk
+const PROTECTIVE_MBR: u8 = 0xEE;
+/// Partition type bytes of MBR extended partitions, which hold logical partitions
+const EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+/// Limits how many logical partitions are followed, in case the chain loops
+const MAX_LOGICAL: u32 = 128;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// Limits the size of the GPT entry array read, as a corrupt header can ask for far more
+const MAX_GPT_ENTRIES_SIZE: usize = 1024 * 1024;
+
+/// Kind of partition table on the disk
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TableKind {
+    Mbr,
+    Gpt,
+}
+
+/// A partition in the partition table. Positions are in bytes
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// Number of the partition as Linux numbers it, starting at 1.
+    /// Logical MBR partitions start at 5
+    pub number: u32,
+    pub start: u128,
+    pub size: u128,
+    /// Partition type byte for MBR, or type GUID for GPT
+    pub kind: String,
+    /// Only GPT partitions have names
+    pub name: Option<String>,
+}
+
+impl Partition {
+    /// Position of the first byte after the partition
+    pub fn end(&self) -> u128 {
+        self.start + self.size
+    }
+}
+
+/// Partitions of a disk and the table they were read from
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartitionTable {
+    pub kind: TableKind,
+    /// The primary GPT could not be read, so the backup at the end of the disk was used
+    pub backup: bool,
+    pub partitions: Vec<Partition>,
+}
+
+/// A partition along with how much of it was recovered
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartitionHealth {
+    #[serde(flatten)]
+    pub partition: Partition,
+    /// Bytes that are non-trimmed, non-scraped or bad sectors
+    pub bad_bytes: u128,
+    /// Bytes that are non-tried, including any past the end of the map file
+    pub untried_bytes: u128,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionError {
+    /// No MBR or GPT was found
+    NoTable,
+    /// The sectors holding the partition table could not be read
+    Unreadable,
+    /// The image file could not be opened
+    Io(String),
+}
+
+impl fmt::Display for PartitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartitionError::NoTable => write!(f, "No partition table found"),
+            PartitionError::Unreadable => {
+                write!(f, "Partition table is in an area that could not be read")
+            }
+            PartitionError::Io(error) => write!(f, "Unable to read image file: {error}"),
+        }
+    }
+}
+
+/// Reads the partition table of a disk of disk_size bytes with the given logical sector size.
+/// read is given a position and length in bytes and returns None if any of it is unreadable,
+/// such as bad sectors. If the primary GPT is unreadable, the backup at the end of the disk is used
+pub fn read_partitions(
+    mut read: impl FnMut(u64, usize) -> Option<Vec<u8>>,
+    disk_size: u64,
+    sector_size: u32,
+) -> Result<PartitionTable, PartitionError> {
+    let sector = sector_size as u64;
+    let mbr = read(0, 512);
+
+    if let Some(mbr) = &mbr {
+        if mbr[510..512] == [0x55, 0xAA] && !mbr_entries(mbr).any(|(_, e)| e.kind == PROTECTIVE_MBR)
+        {
+            return Ok(PartitionTable {
+                kind: TableKind::Mbr,
+                backup: false,
+                partitions: read_mbr(mbr, &mut read, sector),
+            });
+        }
+    }
+
+    // either a protective MBR or an unreadable one, which may still be a GPT disk
+    let last_lba = (disk_size / sector).saturating_sub(1);
+    for header_lba in [1, last_lba] {
+        if let Some(partitions) = read_gpt(&mut read, header_lba, sector) {
+            if header_lba != 1 {
+                info!("Primary GPT unreadable, using the backup GPT");
+            }
+            return Ok(PartitionTable {
+                kind: TableKind::Gpt,
+                backup: header_lba != 1,
+                partitions,
+            });
+        }
+    }
+
+    match mbr {
+        Some(_) => Err(PartitionError::NoTable),
+        None => Err(PartitionError::Unreadable),
+    }
+}
+
+/// Reads the partition table of an image file. Areas that are not finished in the map file
+/// are treated as unreadable, as the image holds zeros or stale data there
+pub fn read_image_partitions(
+    image: &Path,
+    mapfile: &Mapfile,
+    sector_size: u32,
+) -> Result<PartitionTable, PartitionError> {
+    let file = File::open(image).map_err(|e| PartitionError::Io(e.to_string()))?;
+    let read = |pos: u64, len: usize| {
+        if !mapfile.is_finished(pos as u128..pos as u128 + len as u128) {
+            return None;
+        }
+        let mut buffer = vec![0; len];
+        file.read_exact_at(&mut buffer, pos).ok().map(|_| buffer)
+    };
+    read_partitions(read, mapfile.end() as u64, sector_size)
+}
+
+/// Counts the bad and untried bytes of each partition in the map file
+pub fn partition_health(table: &PartitionTable, mapfile: &Mapfile) -> Vec<PartitionHealth> {
+    table
+        .partitions
+        .iter()
+        .map(|partition| {
+            let range = partition.start..partition.end();
+            let bad = [
+                BlockStatus::NonTrimmed,
+                BlockStatus::NonScraped,
+                BlockStatus::BadSector,
+            ];
+            let past_end = partition
+                .end()
+                .saturating_sub(mapfile.end().max(partition.start));
+            PartitionHealth {
+                partition: partition.clone(),
+                bad_bytes: bad
+                    .iter()
+                    .map(|status| mapfile.status_bytes(range.clone(), *status))
+                    .sum(),
+                untried_bytes: mapfile.status_bytes(range, BlockStatus::NonTried) + past_end,
+            }
+        })
+        .collect()
+}
+
+/// An entry of the MBR or an extended boot record
+struct MbrEntry {
+    kind: u8,
+    /// In sectors, relative to the start of the disk or extended partition
+    start: u64,
+    size: u64,
+}
+
+/// Gets the used entries of an MBR or extended boot record, along with their slot
+fn mbr_entries(sector: &[u8]) -> impl Iterator<Item = (usize, MbrEntry)> + '_ {
+    (0..4).filter_map(|slot| {
+        let entry = &sector[446 + slot * 16..446 + (slot + 1) * 16];
+        let entry = MbrEntry {
+            kind: entry[4],
+            start: u32_at(entry, 8) as u64,
+            size: u32_at(entry, 12) as u64,
+        };
+        (entry.kind != 0 && entry.size != 0).then_some((slot, entry))
+    })
+}
+
+fn read_mbr(
+    mbr: &[u8],
+    read: &mut impl FnMut(u64, usize) -> Option<Vec<u8>>,
+    sector: u64,
+) -> Vec<Partition> {
+    let mut partitions = Vec::new();
+
+    for (slot, entry) in mbr_entries(mbr) {
+        // the extended partition itself only holds the logical partitions
+        if EXTENDED.contains(&entry.kind) {
+            read_logical(entry.start, read, sector, &mut partitions);
+        } else {
+            partitions.push(mbr_partition(slot as u32 + 1, &entry, 0, sector));
+        }
+    }
+    partitions.sort_by_key(|partition| partition.number);
+    partitions
+}
+
+/// Follows the chain of extended boot records in an extended partition
+fn read_logical(
+    extended_start: u64,
+    read: &mut impl FnMut(u64, usize) -> Option<Vec<u8>>,
+    sector: u64,
+    partitions: &mut Vec<Partition>,
+) {
+    let mut ebr_lba = extended_start;
+    for number in 5..5 + MAX_LOGICAL {
+        let ebr = match read(ebr_lba * sector, 512) {
+            Some(ebr) if ebr[510..512] == [0x55, 0xAA] => ebr,
+            _ => {
+                info!("Extended boot record at sector {ebr_lba} unreadable");
+                return;
+            }
+        };
+
+        let mut entries = mbr_entries(&ebr).map(|(_, entry)| entry);
+        if let Some(logical) = entries.next() {
+            partitions.push(mbr_partition(number, &logical, ebr_lba, sector));
+        }
+        match entries.next() {
+            Some(next) if EXTENDED.contains(&next.kind) => ebr_lba = extended_start + next.start,
+            _ => return,
+        }
+    }
+}
+
+fn mbr_partition(number: u32, entry: &MbrEntry, base: u64, sector: u64) -> Partition {
+    Partition {
+        number,
+        start: ((base + entry.start) * sector) as u128,
+        size: (entry.size * sector) as u128,
+        kind: format!("0x{:02x}", entry.kind),
+        name: None,
+    }
+}
+
+/// Reads the GPT with its header at header_lba, or None if it is unreadable or invalid
+fn read_gpt(
+    read: &mut impl FnMut(u64, usize) -> Option<Vec<u8>>,
+    header_lba: u64,
+    sector: u64,
+) -> Option<Vec<Partition>> {
+    let header = read(header_lba * sector, 92)?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return None;
+    }
+
+    let header_size = (u32_at(&header, 12) as usize).clamp(92, sector as usize);
+    let header = read(header_lba * sector, header_size)?;
+    let mut check = header.clone();
+    check[16..20].fill(0);
+    if crc32fast::hash(&check) != u32_at(&header, 16) {
+        info!("GPT header at sector {header_lba} is corrupt");
+        return None;
+    }
+
+    let entries_lba = u64_at(&header, 72);
+    let count = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    let entries_size = count
+        .checked_mul(entry_size)
+        .filter(|size| *size <= MAX_GPT_ENTRIES_SIZE);
+    if !entry_size.is_power_of_two() || !(128..=sector as usize).contains(&entry_size) {
+        info!("GPT header at sector {header_lba} has invalid entry size {entry_size}");
+        return None;
+    }
+    let Some(entries_size) = entries_size.filter(|_| count <= 1024) else {
+        info!("GPT header at sector {header_lba} has too many entries");
+        return None;
+    };
+    let entries = read(entries_lba.checked_mul(sector)?, entries_size)?;
+    if crc32fast::hash(&entries) != u32_at(&header, 88) {
+        info!("GPT entries at sector {entries_lba} are corrupt");
+        return None;
+    }
+
+    let partitions = entries
+        .chunks(entry_size)
+        .enumerate()
+        .filter(|(_, entry)| entry[0..16].iter().any(|byte| *byte != 0))
+        .map(|(i, entry)| {
+            let first = u64_at(entry, 32);
+            let last = u64_at(entry, 40);
+            let name: Vec<u16> = entry[56..128]
+                .chunks(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|c| *c != 0)
+                .collect();
+            // positions that do not fit make the whole table invalid
+            let size = last.checked_add(1)?.saturating_sub(first);
+            Some(Partition {
+                number: i as u32 + 1,
+                start: first.checked_mul(sector)? as u128,
+                size: size.checked_mul(sector)? as u128,
+                kind: format_guid(&entry[0..16]),
+                name: Some(String::from_utf16_lossy(&name)),
+            })
+        })
+        .collect::<Option<Vec<Partition>>>();
+    if partitions.is_none() {
+        info!("GPT entries at sector {entries_lba} are out of range");
+    }
+    partitions
+}
+
+/// Formats a GUID stored in the mixed endian layout GPT uses
+fn format_guid(bytes: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        u32_at(bytes, 0),
+        u16::from_le_bytes([bytes[4], bytes[5]]),
+        u16::from_le_bytes([bytes[6], bytes[7]]),
+        bytes[8],
+        bytes[9],
+        bytes[10..16]
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<String>()
+    )
+}
+
+fn u32_at(bytes: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap())
+}
+
+fn u64_at(bytes: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap())
+}
@@ -0,0 +1,182 @@
+//! Reads the map files of a device and fits them to its images, ready for creating its table
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{Seek, SeekFrom},
+};
+
+use crate::align::{align, check_block_size, AlignPolicy};
+use crate::error::{self, FileType};
+use crate::mapfile::Mapfile;
+use crate::parser::{parse_map, parse_map_contents, read_map, ParseOptions};
+use crate::table::MountOptions;
+use log::{error, info};
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A map file as it was read, so that what is recorded matches the table made from it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapSnapshot {
+    /// SHA-256 of the contents that were parsed, as hex
+    pub hash: String,
+    /// Modification time in seconds since the Unix epoch, taken before the contents were read
+    /// so a write during the read shows up as a later change
+    pub modified: Option<u64>,
+}
+
+impl MapSnapshot {
+    pub fn new(contents: &str, modified: Option<u64>) -> MapSnapshot {
+        let hash = Sha256::digest(contents.as_bytes());
+        MapSnapshot {
+            hash: hash.iter().map(|byte| format!("{byte:02x}")).collect(),
+            modified,
+        }
+    }
+}
+
+/// Parses the map file and fits it to the image, the window and the sector size,
+/// ready for [`create_table`](crate::table::create_table). Also returns a snapshot of the contents that were parsed
+pub fn prepare_map(
+    image: &OsString,
+    map: &OsString,
+    options: &ParseOptions,
+    mount_options: &MountOptions,
+) -> (Mapfile, MapSnapshot) {
+    let block_size = options.block_size;
+    if let Err(e) = check_block_size(block_size) {
+        error!("{e}");
+        error::sector_error();
+    }
+
+    let modified = modified_time(map);
+    let contents = read_map(map);
+    let mapfile = parse_map_contents(map, &contents, options);
+    (
+        fit_map(mapfile, image, options, mount_options),
+        MapSnapshot::new(&contents, modified),
+    )
+}
+
+/// Parses the map file of every image and fits it to the image, the window and the sector size,
+/// along with a snapshot of each. Every image must be the same size, as they are images of the same disk
+pub fn prepare_maps(
+    images: &[(OsString, OsString)],
+    options: &ParseOptions,
+    mount_options: &MountOptions,
+) -> (Vec<Mapfile>, Vec<MapSnapshot>) {
+    let (mapfiles, snapshots): (Vec<Mapfile>, Vec<MapSnapshot>) = images
+        .iter()
+        .map(|(image, map)| prepare_map(image, map, options, mount_options))
+        .unzip();
+
+    let range =
+        |mapfile: &Mapfile| mapfile.blocks.first().map_or(0, |block| block.pos)..mapfile.end();
+    for (mapfile, (image, _)) in mapfiles.iter().zip(images).skip(1) {
+        if range(mapfile) != range(&mapfiles[0]) {
+            error!("{:?} does not cover {:?}", image, range(&mapfiles[0]));
+            error::layer_size_error(image);
+        }
+    }
+    (mapfiles, snapshots)
+}
+
+/// Fits a parsed map file to the image, the window and the sector size
+pub fn fit_map(
+    mut mapfile: Mapfile,
+    image: &OsString,
+    options: &ParseOptions,
+    mount_options: &MountOptions,
+) -> Mapfile {
+    let block_size = options.block_size;
+    let image_size = image_size(image);
+    if mapfile.end() != image_size as u128 {
+        if mount_options.clip {
+            // device mapper only addresses whole 512-byte sectors, so a partial one at the end is left out
+            let clip_size = image_size as u128 - image_size as u128 % 512;
+            info!(
+                "Clipping map file from {} to {clip_size} bytes",
+                mapfile.end()
+            );
+            mapfile.clip(clip_size);
+            if clip_size != image_size as u128 {
+                println!(
+                    "Last {} bytes of the image are left out as they are not a whole sector",
+                    image_size as u128 - clip_size
+                );
+            }
+        } else {
+            error!("Map file does not match the size of the image");
+            error::image_size_error(mapfile.end(), image_size);
+        }
+    }
+
+    // restrict the map file to the part of the image that will be presented
+    let offset = mount_options.offset;
+    let end = match mount_options.size {
+        Some(size) => offset.checked_add(size).unwrap_or_else(|| {
+            error!("Offset {offset} and size {size} overflow");
+            error::window_error()
+        }),
+        None => mapfile.end(),
+    };
+    let mut window = offset..end;
+    if let Some(domain_map) = &mount_options.domain_map {
+        let domain = parse_map(
+            domain_map,
+            &ParseOptions {
+                max_errors: options.max_errors,
+                ..Default::default()
+            },
+        );
+        let span = domain.finished_span().unwrap_or(0..0);
+        info!("Domain covers {:?}", span);
+        window = window.start.max(span.start)..window.end.min(span.end);
+        mapfile = mapfile.restrict(&domain);
+    }
+    if window != (0..mapfile.end()) {
+        let sector = block_size as u128;
+        if window.start >= window.end
+            || window.end > mapfile.end()
+            || !window.start.is_multiple_of(sector)
+            || !window.end.is_multiple_of(sector)
+        {
+            error!("Invalid window {:?}", window);
+            error::window_error();
+        }
+        info!("Presenting {:?} of the image", window);
+        mapfile = mapfile.slice(window);
+    }
+
+    if options.align != AlignPolicy::Strict {
+        let reclassified = align(&mut mapfile, block_size, options.align)
+            .unwrap_or_else(|_| error::sector_error());
+        println!("{reclassified} bytes reclassified to align with {block_size}-byte sectors");
+    }
+
+    mapfile
+}
+
+/// Converts time to seconds since the Unix epoch
+pub fn unix_time(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .ok()
+}
+
+/// Gets the modification time of a file in seconds since the Unix epoch
+pub fn modified_time(path: &OsString) -> Option<u64> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(unix_time)
+}
+
+/// Gets the size of the image. Seeking is used rather than metadata so block devices work too
+fn image_size(image: &OsString) -> u64 {
+    File::open(image)
+        .and_then(|mut file| file.seek(SeekFrom::End(0)))
+        .unwrap_or_else(|e| {
+            error::check_io_error(e, image.to_string_lossy().to_string(), FileType::ImageFile)
+        })
+}
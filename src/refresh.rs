@@ -0,0 +1,136 @@
+use std::ffi::OsString;
+
+use crate::align::AlignPolicy;
+use crate::config::{Config, ConfigEntry, PatternLocation, DM_LOCATION};
+use crate::doctor;
+use crate::error;
+use crate::kernel::dmsetup;
+use crate::mapfile::Mapfile;
+use crate::parser::ParseOptions;
+use crate::prepare::{prepare_maps, MapSnapshot};
+use crate::table::{create_composite_table, Layer, MountOptions};
+use log::{error, info};
+
+/// Map files and options a device was mounted with
+pub struct MountedMap {
+    /// Image and map file of every image of the device, in order of precedence
+    pub images: Vec<(OsString, OsString)>,
+    pub options: ParseOptions,
+    pub mount_options: MountOptions,
+}
+
+impl MountedMap {
+    /// Gets the map files and options of a device, exiting if they were not recorded
+    pub fn new(device: &ConfigEntry) -> MountedMap {
+        match (&device.map_file, device.block_size, &device.options) {
+            (Some(map), Some(block_size), Some(mount_options)) => MountedMap {
+                images: [(device.image_file.clone(), map.clone())]
+                    .into_iter()
+                    .chain(
+                        device
+                            .layers
+                            .iter()
+                            .map(|layer| (layer.image_file.clone(), layer.map_file.clone())),
+                    )
+                    .collect(),
+                options: ParseOptions {
+                    max_errors: None,
+                    block_size,
+                    align: device.align.unwrap_or(AlignPolicy::Strict),
+                    check_alignment: true,
+                },
+                mount_options: mount_options.clone(),
+            },
+            _ => error::no_mount_options_error(&device.dm_mount_point),
+        }
+    }
+}
+
+/// Finds a device in the configuration by name, exiting if it is not there
+pub fn find_device(config: &Config, device_name: &str) -> (u32, ConfigEntry) {
+    config
+        .entries()
+        .find(|(_, device)| device.dm_mount_point == device_name)
+        .map(|(entry, device)| (*entry, device.clone()))
+        .unwrap_or_else(|| {
+            error!("Could not find device");
+            error::no_refresh_device(device_name.to_string())
+        })
+}
+
+/// Creates the table for the map files and swaps it in for the current table of the device.
+/// The new table is loaded as the inactive table first, so reads never see a partial table
+pub fn swap_table(device: &ConfigEntry, mapfiles: &[Mapfile], mount_options: &MountOptions) {
+    let device_name = &device.dm_mount_point;
+    let loop_devices = [&device.image_mount_point]
+        .into_iter()
+        .chain(device.layers.iter().map(|layer| &layer.image_mount_point));
+    let layers: Vec<Layer> = mapfiles
+        .iter()
+        .zip(loop_devices)
+        .map(|(mapfile, device)| Layer { mapfile, device })
+        .collect();
+    let pattern = device.pattern.as_ref().map(PatternLocation::device);
+    let table = create_composite_table(&layers, pattern.as_ref(), &mount_options.policy)
+        .unwrap_or_else(|e| {
+            error!("{e}");
+            error::refresh_error()
+        });
+
+    if dmsetup(&["load", device_name], Some(&table)).is_err() {
+        let _ = dmsetup(&["clear", device_name], None);
+        error::refresh_error();
+    }
+    if dmsetup(&["resume", device_name], None).is_err() {
+        error::refresh_error();
+    }
+}
+
+/// Records the snapshots of the map files the table of the device was made from,
+/// in the same order as the images
+pub fn record_maps(
+    config: &mut Config,
+    entry: u32,
+    mut device: ConfigEntry,
+    snapshots: &[MapSnapshot],
+) {
+    if let Some(snapshot) = snapshots.first() {
+        device.map_hash = Some(snapshot.hash.clone());
+        device.map_modified = snapshot.modified;
+    }
+    for (layer, snapshot) in device.layers.iter_mut().zip(snapshots.iter().skip(1)) {
+        layer.map_hash = Some(snapshot.hash.clone());
+        layer.map_modified = snapshot.modified;
+    }
+    config.write_device(entry, device);
+}
+
+/// Parses the map file of a mounted device again and swaps in the new table, so that
+/// areas recovered since it was mounted become readable. Anything using the device,
+/// like a mounted filesystem, keeps working as the device is never removed
+pub fn refresh(device_name: String) {
+    let mut config = Config::lock_config();
+    doctor::reconcile(&mut config).print_changes();
+    info!("Refreshing device {device_name}");
+
+    let (entry, device) = find_device(&config, &device_name);
+    let MountedMap {
+        images,
+        options,
+        mount_options,
+    } = MountedMap::new(&device);
+
+    let (mapfiles, snapshots) = prepare_maps(&images, &options, &mount_options);
+    swap_table(&device, &mapfiles, &mount_options);
+    record_maps(&mut config, entry, device, &snapshots);
+    config.commit();
+
+    let maps: Vec<_> = images
+        .iter()
+        .map(|(_, map)| map.to_string_lossy())
+        .collect();
+    println!(
+        "{DM_LOCATION}{device_name} refreshed from {}",
+        maps.join(", ")
+    );
+}
@@ -0,0 +1,269 @@
+use std::fmt;
+use std::ops::Range;
+
+use crate::mapfile::{BlockStatus, CurrentStatus, Mapfile};
+use serde::Serialize;
+
+/// Upper bounds of the size classes of bad extents in the distribution, in bytes
+const SIZE_CLASSES: [u128; 6] = [
+    512,
+    4 * 1024,
+    64 * 1024,
+    1024 * 1024,
+    16 * 1024 * 1024,
+    u128::MAX,
+];
+
+/// Bytes and number of areas with a status
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatusCount {
+    pub bytes: u128,
+    pub areas: usize,
+}
+
+/// Bytes and areas of each status in the map file
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatusCounts {
+    pub non_tried: StatusCount,
+    pub non_trimmed: StatusCount,
+    pub non_scraped: StatusCount,
+    pub bad_sector: StatusCount,
+    pub finished: StatusCount,
+}
+
+impl StatusCounts {
+    fn get_mut(&mut self, status: BlockStatus) -> &mut StatusCount {
+        match status {
+            BlockStatus::NonTried => &mut self.non_tried,
+            BlockStatus::NonTrimmed => &mut self.non_trimmed,
+            BlockStatus::NonScraped => &mut self.non_scraped,
+            BlockStatus::BadSector => &mut self.bad_sector,
+            BlockStatus::Finished => &mut self.finished,
+        }
+    }
+
+    fn iter(&self) -> [(BlockStatus, StatusCount); 5] {
+        [
+            (BlockStatus::NonTried, self.non_tried),
+            (BlockStatus::NonTrimmed, self.non_trimmed),
+            (BlockStatus::NonScraped, self.non_scraped),
+            (BlockStatus::BadSector, self.bad_sector),
+            (BlockStatus::Finished, self.finished),
+        ]
+    }
+}
+
+/// Number of bad extents up to a size
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeClass {
+    /// None for the last class, which has no limit
+    pub max_size: Option<u128>,
+    pub count: usize,
+}
+
+/// Contiguous areas that are non-trimmed, non-scraped or bad sectors, regardless of which
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BadExtents {
+    pub count: usize,
+    pub bytes: u128,
+    pub smallest: Option<u128>,
+    pub largest: Option<u128>,
+    pub distribution: Vec<SizeClass>,
+}
+
+/// Contiguous area in bytes
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Run {
+    pub pos: u128,
+    pub size: u128,
+}
+
+/// Summary of a map file, like `ddrescuelog -t`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub current_pos: u128,
+    pub current_status: CurrentStatus,
+    pub current_pass: Option<u8>,
+    /// Bytes covered by the map file
+    pub size: u128,
+    pub statuses: StatusCounts,
+    /// Percentage of the size that is finished
+    pub rescued_percent: f64,
+    pub bad_extents: BadExtents,
+    /// Largest contiguous finished area
+    pub largest_good_run: Option<Run>,
+}
+
+impl Stats {
+    pub fn new(mapfile: &Mapfile) -> Stats {
+        let mut statuses = StatusCounts::default();
+        for block in &mapfile.blocks {
+            let count = statuses.get_mut(block.status);
+            count.bytes += block.size;
+            count.areas += 1;
+        }
+
+        let size = mapfile.blocks.iter().map(|block| block.size).sum();
+        let rescued_percent = if size == 0 {
+            0.0
+        } else {
+            statuses.finished.bytes as f64 * 100.0 / size as f64
+        };
+
+        let bad = runs(mapfile, |status| {
+            matches!(
+                status,
+                BlockStatus::NonTrimmed | BlockStatus::NonScraped | BlockStatus::BadSector
+            )
+        });
+        let sizes: Vec<u128> = bad.iter().map(|run| run.end - run.start).collect();
+        let distribution = SIZE_CLASSES
+            .iter()
+            .enumerate()
+            .map(|(i, max)| {
+                let min = if i == 0 { 0 } else { SIZE_CLASSES[i - 1] };
+                SizeClass {
+                    max_size: (*max != u128::MAX).then_some(*max),
+                    count: sizes
+                        .iter()
+                        .filter(|size| **size > min && *size <= max)
+                        .count(),
+                }
+            })
+            .collect();
+
+        let largest_good_run = runs(mapfile, |status| status == BlockStatus::Finished)
+            .into_iter()
+            .map(|run| Run {
+                pos: run.start,
+                size: run.end - run.start,
+            })
+            .reduce(|largest, run| {
+                if run.size > largest.size {
+                    run
+                } else {
+                    largest
+                }
+            });
+
+        Stats {
+            current_pos: mapfile.status.current_pos,
+            current_status: mapfile.status.current_status,
+            current_pass: mapfile.status.current_pass,
+            size,
+            statuses,
+            rescued_percent,
+            bad_extents: BadExtents {
+                count: sizes.len(),
+                bytes: sizes.iter().sum(),
+                smallest: sizes.iter().min().copied(),
+                largest: sizes.iter().max().copied(),
+                distribution,
+            },
+            largest_good_run,
+        }
+    }
+}
+
+/// Merges neighbouring blocks whose status matches into contiguous runs
+fn runs(mapfile: &Mapfile, matches: impl Fn(BlockStatus) -> bool) -> Vec<Range<u128>> {
+    let mut runs: Vec<Range<u128>> = Vec::new();
+    for block in mapfile.blocks.iter().filter(|block| matches(block.status)) {
+        match runs.last_mut() {
+            Some(last) if last.end == block.pos => last.end = block.end(),
+            _ => runs.push(block.pos..block.end()),
+        }
+    }
+    runs
+}
+
+/// Formats bytes along with the size in binary units, like 1048576 (1.00 MiB)
+fn format_bytes(bytes: u128) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{bytes} ({size:.2} {})", UNITS[unit])
+}
+
+/// Formats a size that is a whole number of binary units, like 64 KiB
+fn short_size(bytes: u128) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes;
+    let mut unit = 0;
+    while size >= 1024 && size.is_multiple_of(1024) && unit < units.len() - 1 {
+        size /= 1024;
+        unit += 1;
+    }
+    format!("{size} {}", units[unit])
+}
+
+fn status_name(status: BlockStatus) -> &'static str {
+    match status {
+        BlockStatus::NonTried => "non-tried",
+        BlockStatus::NonTrimmed => "non-trimmed",
+        BlockStatus::NonScraped => "non-scraped",
+        BlockStatus::BadSector => "bad-sector",
+        BlockStatus::Finished => "finished",
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pass = self
+            .current_pass
+            .map(|pass| format!(", pass {pass}"))
+            .unwrap_or_default();
+        writeln!(
+            f,
+            "current pos: 0x{:08X}  status: {}{pass}",
+            self.current_pos,
+            format!("{:?}", self.current_status).to_lowercase()
+        )?;
+        writeln!(f, "size:        {}", format_bytes(self.size))?;
+        writeln!(f, "rescued:     {:.4}%", self.rescued_percent)?;
+        writeln!(f)?;
+
+        writeln!(f, "{:<16} {:>30} {:>10}", "status", "bytes", "areas")?;
+        for (status, count) in self.statuses.iter() {
+            writeln!(
+                f,
+                "{:<16} {:>30} {:>10}",
+                format!("{} ({})", status_name(status), status.as_char()),
+                format_bytes(count.bytes),
+                count.areas
+            )?;
+        }
+        writeln!(f)?;
+
+        let bad = &self.bad_extents;
+        writeln!(f, "bad extents: {}, {}", bad.count, format_bytes(bad.bytes))?;
+        if let (Some(smallest), Some(largest)) = (bad.smallest, bad.largest) {
+            writeln!(f, "  smallest:  {}", format_bytes(smallest))?;
+            writeln!(f, "  largest:   {}", format_bytes(largest))?;
+        }
+        for class in &bad.distribution {
+            let label = match class.max_size {
+                Some(max) => format!("<= {}", short_size(max)),
+                None => "larger".to_string(),
+            };
+            writeln!(f, "  {label:<11}{:>10}", class.count)?;
+        }
+
+        match self.largest_good_run {
+            Some(run) => write!(
+                f,
+                "largest good run: {} at 0x{:08X}",
+                format_bytes(run.size),
+                run.pos
+            ),
+            None => write!(f, "largest good run: none"),
+        }
+    }
+}
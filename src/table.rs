@@ -0,0 +1,347 @@
+use crate::combine::walk;
+use crate::error;
+use crate::mapfile::{BlockStatus, Mapfile};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt::{self, Write};
+
+/// Device mapper target used for an extent of the table
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    /// Reads are passed through to the image
+    Linear,
+    /// Reads fail with an I/O error
+    Error,
+    /// Reads return zeros
+    Zero,
+    /// Reads return a repeating marker from the pattern device
+    Pattern,
+}
+
+/// Size of the pattern file in bytes
+pub const PATTERN_SIZE: u64 = 1024 * 1024;
+/// Copies of the device below that make up each device stacked on the pattern file
+pub const PATTERN_FAN_OUT: u32 = 16;
+
+/// Device that pattern targets read from, repeating the marker at every sector
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternDevice {
+    pub device: String,
+    /// Size in bytes, so that extents larger than this are split into several lines
+    pub size: u128,
+}
+
+impl PatternDevice {
+    /// Loop device of a file of [`PATTERN_SIZE`] bytes created with [`pattern_sector`]
+    pub fn new(device: impl Into<String>) -> PatternDevice {
+        PatternDevice {
+            device: device.into(),
+            size: PATTERN_SIZE as u128,
+        }
+    }
+}
+
+/// Size in bytes of the device at level of the stack on the pattern file, level 0 being the file
+pub fn pattern_stack_size(level: u32) -> u128 {
+    (PATTERN_SIZE as u128).saturating_mul((PATTERN_FAN_OUT as u128).saturating_pow(level))
+}
+
+/// Number of devices to stack on the pattern file for the top one to cover size bytes,
+/// so that every pattern extent of a device of that size fits in one line of its table
+pub fn pattern_stack_levels(size: u128) -> u32 {
+    let mut levels = 0;
+    while pattern_stack_size(levels) < size {
+        levels += 1;
+    }
+    levels
+}
+
+/// Creates the dmsetup table of a device stacked on the pattern file, made of
+/// [`PATTERN_FAN_OUT`] copies of the device below it
+pub fn pattern_stack_table(below: &PatternDevice) -> String {
+    let sectors = below.size / 512;
+    let mut output = String::new();
+    for copy in 0..PATTERN_FAN_OUT as u128 {
+        error::handle_string_write(writeln!(
+            output,
+            "{} {sectors} linear {} 0",
+            copy * sectors,
+            below.device
+        ));
+    }
+    output
+}
+
+/// Devices that the linear targets of the table read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sources {
+    /// Loop device of the image
+    pub image: String,
+    /// Required for [`Target::Pattern`]
+    pub pattern: Option<PatternDevice>,
+}
+
+impl Sources {
+    pub fn new(image: impl Into<String>) -> Sources {
+        Sources {
+            image: image.into(),
+            pattern: None,
+        }
+    }
+}
+
+/// An image of the disk along with its map file, for [`create_composite_table`]
+#[derive(Debug, Clone, Copy)]
+pub struct Layer<'a> {
+    pub mapfile: &'a Mapfile,
+    /// Loop device of the image
+    pub device: &'a str,
+}
+
+/// Fills a sector with the marker, starting again at every sector so that
+/// file carvers can recognise data that was never recovered
+pub fn pattern_sector(marker: &str) -> [u8; 512] {
+    let mut sector = [0; 512];
+    for (byte, marker) in sector.iter_mut().zip(marker.bytes().cycle()) {
+        *byte = marker;
+    }
+    sector
+}
+
+/// Chooses the target each status in the map file is mapped to. A recorded policy that is
+/// missing a status maps it as the default policy does
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct StatusPolicy {
+    non_tried: Target,
+    non_trimmed: Target,
+    non_scraped: Target,
+    bad_sector: Target,
+    finished: Target,
+}
+
+impl StatusPolicy {
+    pub fn set(&mut self, status: BlockStatus, target: Target) {
+        let slot = match status {
+            BlockStatus::NonTried => &mut self.non_tried,
+            BlockStatus::NonTrimmed => &mut self.non_trimmed,
+            BlockStatus::NonScraped => &mut self.non_scraped,
+            BlockStatus::BadSector => &mut self.bad_sector,
+            BlockStatus::Finished => &mut self.finished,
+        };
+        *slot = target;
+    }
+
+    pub fn target(&self, status: BlockStatus) -> Target {
+        match status {
+            BlockStatus::NonTried => self.non_tried,
+            BlockStatus::NonTrimmed => self.non_trimmed,
+            BlockStatus::NonScraped => self.non_scraped,
+            BlockStatus::BadSector => self.bad_sector,
+            BlockStatus::Finished => self.finished,
+        }
+    }
+
+    /// Checks if any status is mapped to target
+    pub fn uses(&self, target: Target) -> bool {
+        [
+            self.non_tried,
+            self.non_trimmed,
+            self.non_scraped,
+            self.bad_sector,
+            self.finished,
+        ]
+        .contains(&target)
+    }
+}
+
+impl Default for StatusPolicy {
+    /// Finished blocks are read from the image and everything else is an I/O error
+    fn default() -> StatusPolicy {
+        StatusPolicy {
+            non_tried: Target::Error,
+            non_trimmed: Target::Error,
+            non_scraped: Target::Error,
+            bad_sector: Target::Error,
+            finished: Target::Linear,
+        }
+    }
+}
+
+/// Options for how the image is presented, beyond how the map file is parsed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MountOptions {
+    pub policy: StatusPolicy,
+    /// Marker repeated in areas filled with the pattern
+    pub marker: String,
+    /// Present only the size of the image if the map file is larger or smaller
+    pub clip: bool,
+    /// Position in the image where the device starts
+    pub offset: u128,
+    /// Size of the device, up to the end of the map file if not set
+    pub size: Option<u128>,
+    /// ddrescue domain map file restricting the device to its finished blocks
+    pub domain_map: Option<OsString>,
+    /// Create a device for every partition in the partition table of the image
+    #[serde(default)]
+    pub partitions: bool,
+}
+
+/// Reason a table could not be created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableError {
+    /// There were no images to create the table from
+    NoLayers,
+    /// The map file of the image at this index covers a different range than the first
+    LayerRange(usize),
+    /// A status is mapped to [`Target::Pattern`] but there is no pattern device of at least a sector
+    NoPattern,
+}
+
+impl fmt::Display for TableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TableError::NoLayers => write!(f, "{}", error::NO_LAYERS_ERROR),
+            TableError::LayerRange(layer) => write!(
+                f,
+                "{}",
+                error::LAYER_RANGE_ERROR.replace("{layer}", &(layer + 1).to_string())
+            ),
+            TableError::NoPattern => write!(f, "{}", error::NO_PATTERN_ERROR),
+        }
+    }
+}
+
+impl std::error::Error for TableError {}
+
+/// A line of the dmsetup table. All values are in 512-byte sectors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Entry {
+    start: u128,
+    size: u128,
+    target: Target,
+    /// Offset into the image for linear targets
+    offset: u128,
+    /// Layer that linear targets read from
+    layer: usize,
+}
+
+impl Entry {
+    /// Checks if other carries on directly from this entry with the same target
+    fn continues_with(&self, other: &Entry) -> bool {
+        self.target == other.target
+            && self.start + self.size == other.start
+            && (self.target != Target::Linear
+                || (self.layer == other.layer && self.offset + self.size == other.offset))
+    }
+}
+
+/// Creates dmsetup table from map file, with the linear targets pointing at the sources.
+/// The device starts at the first block, so a map file from [`Mapfile::slice`] presents
+/// only that part of the image. Neighbouring extents that map to the same target are
+/// merged into one line. Fails if a status is mapped to [`Target::Pattern`] but sources has no pattern
+pub fn create_table(
+    mapfile: &Mapfile,
+    sources: &Sources,
+    policy: &StatusPolicy,
+) -> Result<String, TableError> {
+    create_composite_table(
+        &[Layer {
+            mapfile,
+            device: &sources.image,
+        }],
+        sources.pattern.as_ref(),
+        policy,
+    )
+}
+
+/// Creates dmsetup table from several images of the same disk, in order of precedence.
+/// Each extent is read from the first image that has it finished, then from the first one
+/// whose status is mapped to [`Target::Linear`]. Otherwise the status in the first image
+/// chooses the target, so extents are only errors if no image rescued them.
+/// The map files must cover the same range of the disk. pattern is required for
+/// [`Target::Pattern`], and pattern extents larger than it are split into several lines
+pub fn create_composite_table(
+    layers: &[Layer],
+    pattern: Option<&PatternDevice>,
+    policy: &StatusPolicy,
+) -> Result<String, TableError> {
+    let first = layers.first().ok_or(TableError::NoLayers)?.mapfile;
+    let range =
+        |mapfile: &Mapfile| mapfile.blocks.first().map_or(0, |block| block.pos)..mapfile.end();
+    if let Some(layer) = layers
+        .iter()
+        .position(|layer| range(layer.mapfile) != range(first))
+    {
+        return Err(TableError::LayerRange(layer));
+    }
+
+    let mut entries: Vec<Entry> = Vec::new();
+    let base = range(first).start;
+    let mapfiles: Vec<&Mapfile> = layers.iter().map(|layer| layer.mapfile).collect();
+
+    walk(&mapfiles, |range, statuses| {
+        let layer = statuses
+            .iter()
+            .position(|status| *status == BlockStatus::Finished)
+            .or_else(|| {
+                statuses
+                    .iter()
+                    .position(|status| policy.target(*status) == Target::Linear)
+            })
+            .unwrap_or(0);
+        let entry = Entry {
+            start: (range.start - base) / 512,
+            size: (range.end - range.start) / 512,
+            target: policy.target(statuses[layer]),
+            offset: range.start / 512,
+            layer,
+        };
+
+        match entries.last_mut() {
+            Some(last) if last.continues_with(&entry) => last.size += entry.size,
+            _ => entries.push(entry),
+        }
+    });
+
+    let mut output = String::new();
+
+    for Entry {
+        start,
+        size,
+        target,
+        offset,
+        layer,
+    } in entries
+    {
+        match target {
+            Target::Linear => error::handle_string_write(writeln!(
+                output,
+                "{start} {size} linear {} {offset}",
+                layers[layer].device
+            )),
+            Target::Error => error::handle_string_write(writeln!(output, "{start} {size} error")),
+            Target::Zero => error::handle_string_write(writeln!(output, "{start} {size} zero")),
+            Target::Pattern => {
+                let pattern = pattern
+                    .filter(|pattern| pattern.size >= 512)
+                    .ok_or(TableError::NoPattern)?;
+                // The pattern device is repeated if it is smaller than the bad area
+                let pattern_sectors = pattern.size / 512;
+                let mut chunk_start = start;
+                while chunk_start < start + size {
+                    let chunk = pattern_sectors.min(start + size - chunk_start);
+                    error::handle_string_write(writeln!(
+                        output,
+                        "{chunk_start} {chunk} linear {} 0",
+                        pattern.device
+                    ));
+                    chunk_start += chunk;
+                }
+            }
+        }
+    }
+
+    Ok(output)
+}
@@ -1,7 +1,13 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
 use std::process::Command;
 
-use crate::config::{self, Config, Device};
+use crate::config::{self, Config, Device, Overlay, DM_LOCATION};
+use crate::doctor;
 use crate::error;
+use crate::filesystem::unmount_filesystem;
+use crate::kernel;
 use log::{error, info};
 
 pub enum ImageError {
@@ -9,15 +15,56 @@ pub enum ImageError {
     HideError,
 }
 
-/// Unmounts a device
-pub fn unmount(device_name: String) {
-    let mut config = Config::read_config();
+/// Unmounts a device. keep_overlay chooses whether the file holding the writes of a
+/// writable device is kept, asking if it is None
+pub fn unmount(device_name: String, keep_overlay: Option<bool>) {
+    let mut config = Config::lock_config();
     info!("Unmounting device {device_name}");
+
+    let report = doctor::reconcile(&mut config);
+    if let Some(entry) = report
+        .dropped
+        .iter()
+        .find(|entry| entry.dm_mount_point == device_name)
+    {
+        // the device mapper device is already gone, so only its loop devices are left
+        let pattern = entry.pattern.as_ref().map(|pattern| &pattern.file);
+        let overlay = entry.overlay.as_ref().map(|overlay| &overlay.file);
+        let is_image = |file: &OsString| {
+            *file == entry.image_file
+                || entry.layers.iter().any(|layer| layer.image_file == *file)
+                || Some(file) == overlay
+        };
+        // devices stacked on the pattern file hold its loop device, so it is not left over
+        if let Some(stacked) = entry.pattern.as_ref().filter(|p| !p.devices.is_empty()) {
+            for name in stacked.devices.iter().rev() {
+                let _ = kernel::dmsetup(&["remove", name], None);
+            }
+            let _ = unmount_image(stacked.mount_point.clone(), ImageError::HideError);
+        }
+        for device in &report.leftover {
+            if is_image(&device.backing_file) || Some(&device.backing_file) == pattern {
+                let _ = unmount_image(device.device.clone(), ImageError::HideError);
+            }
+        }
+        if let Some(pattern) = pattern {
+            let _ = fs::remove_file(pattern);
+        }
+        if let Some(overlay) = &entry.overlay {
+            remove_overlay_file(overlay, keep_overlay);
+        }
+        config.commit();
+        println!("Device {device_name} unmounted");
+        return;
+    }
+    report.print_changes();
+
     let mut devices = config.iter_mut();
     let search = devices.find(|d| d.device_mount_point == device_name);
 
     if let Some(device) = search {
-        unmount_device(device, Some(&mut config));
+        unmount_device(device, Some(&mut config), keep_overlay);
+        config.commit();
         info!("Successfully unmounted device");
     } else {
         error!("Could not find device");
@@ -44,7 +91,7 @@ pub fn unmount_device_mapper(name: &str) {
 }
 
 /// Unmounts image from losetup
-pub fn unmount_image(name: String, error: ImageError) -> Result<(), ()> {
+pub(crate) fn unmount_image(name: String, error: ImageError) -> Result<(), ()> {
     info!("losetup -d {name}");
     let output = Command::new("losetup").args(["-d", &name]).output();
 
@@ -66,11 +113,69 @@ pub fn unmount_image(name: String, error: ImageError) -> Result<(), ()> {
     Ok(())
 }
 
+/// Asks whether to keep the file holding the writes of a writable device, unless already chosen.
+/// The file is kept if there is no terminal to ask on
+fn remove_overlay_file(overlay: &Overlay, keep: Option<bool>) {
+    let file = overlay.file.to_string_lossy();
+    let keep = keep.unwrap_or_else(|| {
+        if !io::stdin().is_terminal() {
+            return true;
+        }
+        print!(
+            "Keep the writes made to {DM_LOCATION}{} in {file}? [Y/n] ",
+            overlay.name
+        );
+        let _ = io::stdout().flush();
+        let mut answer = String::new();
+        let _ = io::stdin().read_line(&mut answer);
+        !matches!(answer.trim(), "n" | "N" | "no" | "No")
+    });
+
+    if keep {
+        println!("Writes kept in {file}");
+    } else if let Err(e) = fs::remove_file(&overlay.file) {
+        error!("Unable to remove overlay file {:?}: {:?}", overlay.file, e);
+    } else {
+        println!("Writes in {file} discarded");
+    }
+}
+
 /// Function that accepts a specific device and removes it from config
-fn unmount_device(device: Device, config: Option<&mut Config>) {
+fn unmount_device(device: Device, config: Option<&mut Config>, keep_overlay: Option<bool>) {
     let entry = format!("{}{}", config::DEVICE_NAME, device.get_entry());
+    if let Some(filesystem) = device.get_filesystem() {
+        if unmount_filesystem(&filesystem).is_err() {
+            error::filesystem_unmount_error();
+        }
+        println!(
+            "Filesystem at {} unmounted",
+            filesystem.mount_point.to_string_lossy()
+        );
+    }
+    for partition in device.get_partitions().iter().rev() {
+        unmount_device_mapper(&partition.name);
+    }
+    if let Some(overlay) = device.get_overlay() {
+        unmount_device_mapper(&overlay.name);
+    }
     unmount_device_mapper(&entry);
     let _ = unmount_image(device.get_image_location(), ImageError::ShowError);
+    for layer in device.get_layers() {
+        let _ = unmount_image(layer.image_mount_point.clone(), ImageError::ShowError);
+    }
+    if let Some(pattern) = device.get_pattern_location() {
+        for name in pattern.devices.iter().rev() {
+            unmount_device_mapper(name);
+        }
+        let _ = unmount_image(pattern.mount_point, ImageError::ShowError);
+        if let Err(e) = fs::remove_file(&pattern.file) {
+            error!("Unable to remove pattern file {:?}: {:?}", pattern.file, e);
+        }
+    }
+    if let Some(overlay) = device.get_overlay() {
+        let _ = unmount_image(overlay.loop_device.clone(), ImageError::ShowError);
+        remove_overlay_file(overlay, keep_overlay);
+    }
     if let Some(config) = config {
         config.remove_device(device.get_entry());
     }
@@ -79,12 +184,15 @@ fn unmount_device(device: Device, config: Option<&mut Config>) {
 
 /// Unmounts all devices
 pub fn unmount_all() {
-    let mut config = Config::read_config();
+    let mut config = Config::lock_config();
+    doctor::reconcile(&mut config).print_changes();
     let devices = config.iter_mut();
 
+    // overlays are kept, as there may be too many to ask about
     for device in devices {
-        unmount_device(device, None);
+        unmount_device(device, None, Some(true));
     }
 
     config.clear_devices();
+    config.commit();
 }
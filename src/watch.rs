@@ -0,0 +1,243 @@
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io::{self, Error as IOError};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::prelude::*;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::combine;
+use crate::config::{Config, DM_LOCATION};
+use crate::error;
+use crate::kernel;
+use crate::parser::{parse_map, parse_with_options};
+use crate::prepare::{fit_map, modified_time, prepare_maps, MapSnapshot};
+use crate::refresh::{find_device, record_maps, swap_table, MountedMap};
+use log::{error, info};
+
+/// How often to check that the device has not been unmounted while the map file is unchanged
+const UNMOUNT_CHECK: Duration = Duration::from_secs(5);
+
+/// Watches folders for changes to files in them using inotify
+struct Watcher {
+    fd: OwnedFd,
+}
+
+impl Watcher {
+    fn new(folders: &[&Path]) -> Result<Watcher, IOError> {
+        // SAFETY: inotify_init1 has no preconditions and the descriptor is owned from here on
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(IOError::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // the folder is watched rather than the file, as the file may be replaced
+        let mask = libc::IN_MODIFY | libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE;
+        for folder in folders {
+            let folder = CString::new(folder.as_os_str().as_bytes())?;
+            // SAFETY: fd is a valid inotify descriptor and folder is a valid C string
+            if unsafe { libc::inotify_add_watch(fd.as_raw_fd(), folder.as_ptr(), mask) } < 0 {
+                return Err(IOError::last_os_error());
+            }
+        }
+
+        Ok(Watcher { fd })
+    }
+
+    /// Waits for any of the files to change, giving up after timeout. Returns true if one changed
+    fn wait(&self, names: &[&OsStr], timeout: Option<Duration>) -> Result<bool, IOError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    left.as_millis().min(i32::MAX as u128) as i32
+                }
+                None => -1,
+            };
+
+            let mut poll = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: poll is given a single valid pollfd
+            match unsafe { libc::poll(&mut poll, 1, timeout) } {
+                0 => return Ok(false),
+                n if n < 0 => {
+                    let error = IOError::last_os_error();
+                    if error.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(error);
+                }
+                _ => (),
+            }
+
+            if self
+                .read_events()?
+                .iter()
+                .any(|event| names.iter().any(|name| event == name.as_bytes()))
+            {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Reads the pending events, returning the names of the files they are for
+    fn read_events(&self) -> Result<Vec<Vec<u8>>, IOError> {
+        let mut buffer = [0u8; 4096];
+        // SAFETY: the buffer is valid for its whole length
+        let length = unsafe {
+            libc::read(
+                self.fd.as_raw_fd(),
+                buffer.as_mut_ptr().cast(),
+                buffer.len(),
+            )
+        };
+        if length < 0 {
+            return Err(IOError::last_os_error());
+        }
+
+        // each event is a struct inotify_event followed by a name padded with nul bytes
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut names = Vec::new();
+        let mut pos = 0;
+        while pos + header <= length as usize {
+            // SAFETY: the kernel only writes whole events, and read_unaligned does not
+            // need the buffer to be aligned
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(buffer[pos..].as_ptr().cast()) };
+            let name = &buffer[pos + header..pos + header + event.len as usize];
+            let end = name
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or(name.len());
+            names.push(name[..end].to_vec());
+            pos += header + event.len as usize;
+        }
+        Ok(names)
+    }
+}
+
+/// Follows the map files of a mounted device while ddrescue is still writing them,
+/// swapping in a new table every time they change. Runs until the device is unmounted
+pub fn watch(device_name: String, debounce: Duration) {
+    let (entry, device) = find_device(&Config::read_config(), &device_name);
+    let MountedMap {
+        images,
+        options,
+        mount_options,
+    } = MountedMap::new(&device);
+
+    let paths: Vec<&Path> = images.iter().map(|(_, map)| Path::new(map)).collect();
+    let names: Vec<&OsStr> = paths
+        .iter()
+        .map(|path| path.file_name().unwrap_or_default())
+        .collect();
+    let folders: Vec<&Path> = paths
+        .iter()
+        .map(|path| path.parent().unwrap_or_else(|| Path::new("/")))
+        .collect();
+    let watcher = Watcher::new(&folders).unwrap_or_else(|e| {
+        error!("Unable to watch {:?}: {:?}", folders, e);
+        error::watch_error()
+    });
+
+    let (mut current, _) = prepare_maps(&images, &options, &mount_options);
+    // ddrescue keeps the size of the disk in the map file, so one that ends anywhere else was
+    // read while it was being written. Fitting it to the image would exit or clip it
+    let ends: Vec<u128> = images
+        .iter()
+        .map(|(_, map)| parse_map(map, &options).end())
+        .collect();
+    let maps: Vec<_> = images
+        .iter()
+        .map(|(_, map)| map.to_string_lossy())
+        .collect();
+    println!(
+        "Following {} for {DM_LOCATION}{device_name}. Press Ctrl+C to stop",
+        maps.join(", ")
+    );
+
+    loop {
+        // wait for a change, checking now and then that the device is still there
+        let result = watcher.wait(&names, Some(UNMOUNT_CHECK));
+        if kernel::dm_state(&device_name).is_none() {
+            println!("{DM_LOCATION}{device_name} was unmounted");
+            return;
+        }
+
+        // then wait for ddrescue to stop writing
+        let result = result.and_then(|changed| {
+            if changed {
+                while watcher.wait(&names, Some(debounce))? {}
+            }
+            Ok(changed)
+        });
+        let changed = result.unwrap_or_else(|e| {
+            error!("Unable to watch {:?}: {:?}", maps, e);
+            error::watch_error()
+        });
+        if !changed {
+            continue;
+        }
+
+        // ddrescue does not write the map file atomically, so it may be read half written
+        let mapfiles: Result<Vec<_>, String> = images
+            .iter()
+            .zip(&ends)
+            .map(|((image, map), end)| {
+                let modified = modified_time(map);
+                let contents = fs::read_to_string(map).map_err(|e| e.to_string())?;
+                let mapfile =
+                    parse_with_options(map, &contents, &options).map_err(|e| e.summary())?;
+                if mapfile.end() != *end {
+                    return Err(format!(
+                        "{} covers {} bytes instead of {end}",
+                        map.to_string_lossy(),
+                        mapfile.end()
+                    ));
+                }
+                Ok((
+                    fit_map(mapfile, image, &options, &mount_options),
+                    MapSnapshot::new(&contents, modified),
+                ))
+            })
+            .collect();
+        let (mapfiles, snapshots): (Vec<_>, Vec<_>) = match mapfiles {
+            Ok(mapfiles) => mapfiles.into_iter().unzip(),
+            Err(e) => {
+                info!("Map file is not ready, waiting for the next change: {e}");
+                continue;
+            }
+        };
+
+        if mapfiles == current {
+            info!("Map file changed but the table did not");
+            continue;
+        }
+
+        swap_table(&device, &mapfiles, &mount_options);
+        // areas are readable if any image rescued them
+        let readable = combine::or(&mapfiles).unwrap();
+        let was_readable = combine::or(&current).unwrap();
+        let recovered = readable.recovered_since(&was_readable);
+        let lost = was_readable.recovered_since(&readable);
+        println!(
+            "{device_name}: {recovered} bytes became readable, {lost} bytes became unreadable"
+        );
+        current = mapfiles;
+
+        let mut config = Config::lock_config();
+        if config
+            .entries()
+            .any(|(e, d)| *e == entry && d.dm_mount_point == device_name)
+        {
+            record_maps(&mut config, entry, device.clone(), &snapshots);
+            config.commit();
+        }
+    }
+}
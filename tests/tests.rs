@@ -2,7 +2,18 @@
 mod tests {
     use std::ffi::OsString;
 
-    use ddr_mount::parse_map_string;
+    use ddr_mount::affected::{bad_bytes, file_extents, unescape_mount};
+    use ddr_mount::combine;
+    use ddr_mount::partitions::{
+        partition_health, read_image_partitions, read_partitions, PartitionError, TableKind,
+    };
+    use ddr_mount::{
+        align, create_composite_table, create_table, export_image, parse, parse_config,
+        parse_first, parse_map_string, parse_with_options, pattern_sector, pattern_stack_levels,
+        pattern_stack_size, pattern_stack_table, AlignPolicy, Block, BlockStatus, CurrentStatus,
+        ExportSummary, Fill, Layer, Mapfile, ParseOptions, PatternDevice, SectorSizeError, Sources,
+        Stats, StatusLine, StatusPolicy, TableError, Target, PATTERN_FAN_OUT, PATTERN_SIZE,
+    };
 
     #[test]
     fn test1() {
@@ -27,4 +38,1048 @@ mod tests {
 
         assert_eq!(output, include_str!("./test3output.txt"));
     }
+
+    #[test]
+    fn parse_mapfile() {
+        let input = include_str!("./test2.txt");
+        let mapfile = parse(&OsString::from("test2.txt"), input).unwrap();
+
+        assert_eq!(mapfile.status.current_pos, 0x3FFF0000);
+        assert_eq!(mapfile.status.current_status, CurrentStatus::Finished);
+        assert_eq!(mapfile.status.current_pass, Some(1));
+        assert_eq!(
+            mapfile.blocks,
+            vec![Block {
+                pos: 0,
+                size: 0x40000000,
+                status: BlockStatus::Finished
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_error() {
+        let input = "0x0 +\n0x0 0x200 +\n0x300 0x200 -\n";
+        let diagnostics = parse(&OsString::from("bad.txt"), input).unwrap_err();
+        let error = &diagnostics.errors()[0];
+
+        assert_eq!(error.filename, "bad.txt");
+        assert_eq!(error.line, 3);
+        assert_eq!(error.span, 0..5);
+    }
+
+    #[test]
+    fn parse_all_errors() {
+        let input = "0x0 +\n0x0 0x2x0 +\n0x400 0x200 !\n0x800 0x100 -\n";
+        let diagnostics = parse(&OsString::from("bad.txt"), input).unwrap_err();
+        let lines: Vec<_> = diagnostics.errors().iter().map(|e| e.line).collect();
+
+        assert_eq!(lines, vec![2, 3, 4, 4]);
+        assert!(!diagnostics.is_truncated());
+    }
+
+    #[test]
+    fn parse_overflow() {
+        let input = "0x0 +\n0x0 0xFFFFFFFFFFFFFFFFFFFFFFFFFFFFFE00 +\n\
+                     0xFFFFFFFFFFFFFFFFFFFFFFFFFFFFFE00 0x400 -\n";
+        let diagnostics = parse(&OsString::from("overflow.txt"), input).unwrap_err();
+        let errors: Vec<_> = diagnostics
+            .errors()
+            .iter()
+            .map(|e| (e.line, e.message.as_str()))
+            .collect();
+
+        assert_eq!(errors, vec![(3, "Position and size overflow")]);
+        assert_eq!(diagnostics.errors()[0].span, 35..40);
+        assert!(parse_first(&OsString::from("overflow.txt"), input).is_err());
+    }
+
+    #[test]
+    fn parse_max_errors() {
+        let input = "0x0 +\n0x0 0x2x0 +\n0x400 0x200 !\n0x800 0x100 -\n";
+        let options = ParseOptions {
+            max_errors: Some(2),
+            ..Default::default()
+        };
+        let diagnostics =
+            parse_with_options(&OsString::from("bad.txt"), input, &options).unwrap_err();
+
+        assert_eq!(diagnostics.errors().len(), 2);
+        assert!(diagnostics.is_truncated());
+    }
+
+    #[test]
+    fn parse_first_error() {
+        let input = "0x0 +\n0x0 0x2x0 +\n0x400 0x200 !\n0x800 0x100 -\n";
+        let error = parse_first(&OsString::from("bad.txt"), input).unwrap_err();
+        assert_eq!(error.line, 2);
+
+        let input = include_str!("./test1.txt");
+        assert!(parse_first(&OsString::from("test1.txt"), input).is_ok());
+    }
+
+    #[test]
+    fn parse_max_errors_reached_at_end() {
+        // the file has exactly 4 errors, so all of it is read even though the limit is reached
+        let input = "0x0 +\n0x0 0x2x0 +\n0x400 0x200 !\n0x800 0x100 -\n";
+        let options = ParseOptions {
+            max_errors: Some(4),
+            ..Default::default()
+        };
+        let diagnostics =
+            parse_with_options(&OsString::from("bad.txt"), input, &options).unwrap_err();
+
+        assert_eq!(diagnostics.errors().len(), 4);
+        assert!(!diagnostics.is_truncated());
+
+        // a limit of 0 does not stop a valid map file from being read
+        let options = ParseOptions {
+            max_errors: Some(0),
+            ..Default::default()
+        };
+        let mapfile = parse_with_options(
+            &OsString::from("test1.txt"),
+            include_str!("./test1.txt"),
+            &options,
+        )
+        .unwrap();
+        assert!(!mapfile.blocks.is_empty());
+    }
+
+    #[test]
+    fn block_size_alignment() {
+        let input = include_str!("./test3.txt");
+        let options = ParseOptions {
+            block_size: 4096,
+            ..Default::default()
+        };
+        let diagnostics =
+            parse_with_options(&OsString::from("test3.txt"), input, &options).unwrap_err();
+        let error = &diagnostics.errors()[0];
+
+        assert_eq!(error.line, 19);
+        assert_eq!(error.span, 13..23);
+    }
+
+    #[test]
+    fn unchecked_alignment() {
+        // a bad area that splits a 512-byte sector, as ddrescue writes for some drives
+        let input = "0x0 +\n0x0 0x300 +\n0x300 0x100 -\n0x400 0x200 +\n";
+        assert!(parse(&OsString::from("odd.map"), input).is_err());
+
+        let options = ParseOptions {
+            check_alignment: false,
+            ..Default::default()
+        };
+        let mapfile = parse_with_options(&OsString::from("odd.map"), input, &options).unwrap();
+        assert_eq!(mapfile.blocks[1].pos, 0x300);
+        assert_eq!(mapfile.blocks[1].size, 0x100);
+    }
+
+    #[test]
+    fn expand_bad() {
+        let input = "0x0 +\n0x0 0x1200 +\n0x1200 0x200 -\n0x1400 0x1C00 +\n0x3000 0x400 *\n";
+        let options = ParseOptions {
+            block_size: 4096,
+            align: AlignPolicy::ExpandBad,
+            ..Default::default()
+        };
+        let mut mapfile = parse_with_options(&OsString::from("map"), input, &options).unwrap();
+        let reclassified = align(&mut mapfile, 4096, AlignPolicy::ExpandBad).unwrap();
+
+        let blocks: Vec<_> = mapfile
+            .blocks
+            .iter()
+            .map(|b| (b.pos, b.size, b.status.as_char()))
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                (0, 0x1000, '+'),
+                (0x1000, 0x1000, '-'),
+                (0x2000, 0x1000, '+')
+            ]
+        );
+        assert_eq!(reclassified, 0xE00);
+    }
+
+    #[test]
+    fn shrink_bad() {
+        let input = "0x0 +\n0x0 0x1200 +\n0x1200 0x200 -\n0x1400 0x1C00 +\n";
+        let options = ParseOptions {
+            block_size: 4096,
+            align: AlignPolicy::ShrinkBad,
+            ..Default::default()
+        };
+        let mut mapfile = parse_with_options(&OsString::from("map"), input, &options).unwrap();
+        let reclassified = align(&mut mapfile, 4096, AlignPolicy::ShrinkBad).unwrap();
+
+        assert_eq!(
+            mapfile.blocks,
+            vec![Block {
+                pos: 0,
+                size: 0x3000,
+                status: BlockStatus::Finished
+            }]
+        );
+        assert_eq!(reclassified, 0x200);
+    }
+
+    #[test]
+    fn invalid_block_size() {
+        let input = "0x0 +\n0x0 0x1200 +\n0x1200 0x200 -\n";
+        let mut mapfile = parse(&OsString::from("map"), input).unwrap();
+        assert_eq!(
+            align(&mut mapfile, 0, AlignPolicy::ExpandBad),
+            Err(SectorSizeError(0))
+        );
+        assert_eq!(
+            align(&mut mapfile, 1000, AlignPolicy::Strict),
+            Err(SectorSizeError(1000))
+        );
+
+        let options = ParseOptions {
+            block_size: 0,
+            ..Default::default()
+        };
+        let diagnostics = parse_with_options(&OsString::from("map"), input, &options).unwrap_err();
+        assert_eq!(diagnostics.errors().len(), 1);
+        assert_eq!(diagnostics.errors()[0].line, 0);
+    }
+
+    #[test]
+    fn merge_table() {
+        let input = include_str!("./test4.txt");
+        let output = parse_map_string(&OsString::from("test4.txt"), input, "/dev/loop##");
+
+        assert_eq!(output, include_str!("./test4output.txt"));
+    }
+
+    /// Splits every line of a table into single sectors described by (target, offset)
+    fn expand_table(table: &str) -> Vec<(String, Option<u128>)> {
+        let mut sectors = Vec::new();
+        for line in table.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let start: u128 = fields[0].parse().unwrap();
+            let size: u128 = fields[1].parse().unwrap();
+            assert_eq!(start, sectors.len() as u128);
+            for sector in 0..size {
+                let offset = fields.get(4).map(|o| o.parse::<u128>().unwrap() + sector);
+                sectors.push((fields[2].to_string(), offset));
+            }
+        }
+        sectors
+    }
+
+    #[test]
+    fn merged_table_coverage() {
+        let input = include_str!("./test4.txt");
+        let mapfile = parse(&OsString::from("test4.txt"), input).unwrap();
+
+        let mut unmerged = String::new();
+        for block in &mapfile.blocks {
+            let (pos, size) = (block.pos / 512, block.size / 512);
+            unmerged += &match block.status {
+                BlockStatus::Finished => format!("{pos} {size} linear /dev/loop## {pos}\n"),
+                _ => format!("{pos} {size} error\n"),
+            };
+        }
+        let merged = create_table(
+            &mapfile,
+            &Sources::new("/dev/loop##"),
+            &StatusPolicy::default(),
+        )
+        .unwrap();
+
+        assert!(merged.lines().count() < unmerged.lines().count());
+        assert_eq!(expand_table(&merged), expand_table(&unmerged));
+    }
+
+    #[test]
+    fn status_policy() {
+        let input = include_str!("./test4.txt");
+        let mapfile = parse(&OsString::from("test4.txt"), input).unwrap();
+        let mut policy = StatusPolicy::default();
+        policy.set(BlockStatus::NonTrimmed, Target::Linear);
+        policy.set(BlockStatus::NonScraped, Target::Linear);
+        policy.set(BlockStatus::NonTried, Target::Zero);
+
+        let table = create_table(&mapfile, &Sources::new("/dev/loop##"), &policy).unwrap();
+        let expected = "\
+0 1910656 linear /dev/loop## 0
+1910656 8 error
+1910664 1784 linear /dev/loop## 1910664
+1912448 32 error
+1912480 352 zero
+1912832 2200 linear /dev/loop## 1912832
+1915032 8 error
+1915040 509792 zero
+";
+        assert_eq!(table, expected);
+    }
+
+    #[test]
+    fn recorded_status_policy() {
+        // written by an earlier version, with every status
+        let recorded = r#"{"non_tried":"zero","non_trimmed":"error","non_scraped":"error","bad_sector":"pattern","finished":"linear"}"#;
+        let policy: StatusPolicy = serde_json::from_str(recorded).unwrap();
+        assert_eq!(policy.target(BlockStatus::NonTried), Target::Zero);
+        assert_eq!(policy.target(BlockStatus::BadSector), Target::Pattern);
+        assert_eq!(serde_json::to_string(&policy).unwrap(), recorded);
+
+        // missing statuses are mapped as the default policy does
+        let policy: StatusPolicy = serde_json::from_str(r#"{"non_tried":"zero"}"#).unwrap();
+        assert_eq!(policy.target(BlockStatus::NonTried), Target::Zero);
+        assert_eq!(policy.target(BlockStatus::NonTrimmed), Target::Error);
+        assert_eq!(policy.target(BlockStatus::Finished), Target::Linear);
+    }
+
+    #[test]
+    fn pattern_fill() {
+        let input = "0x0 +\n0x0 0x1000 +\n0x1000 0x180000 -\n0x181000 0x1000 +\n";
+        let mapfile = parse(&OsString::from("map"), input).unwrap();
+        let mut policy = StatusPolicy::default();
+        policy.set(BlockStatus::BadSector, Target::Pattern);
+        let sources = Sources {
+            image: "/dev/loop##".to_string(),
+            pattern: Some(PatternDevice::new("/dev/loop#p")),
+        };
+
+        let table = create_table(&mapfile, &sources, &policy).unwrap();
+        let expected = "\
+0 8 linear /dev/loop## 0
+8 2048 linear /dev/loop#p 0
+2056 1024 linear /dev/loop#p 0
+3080 8 linear /dev/loop## 3080
+";
+        assert_eq!(table, expected);
+        assert_eq!(&pattern_sector("BADSECTOR")[..12], b"BADSECTORBAD");
+    }
+
+    #[test]
+    fn pattern_stack() {
+        let tib = 1u128 << 40;
+        let input = format!("0x0 ?\n0x0 0x1000 +\n0x1000 0x{:X} ?\n", tib - 0x1000);
+        let mapfile = parse(&OsString::from("map"), &input).unwrap();
+        let mut policy = StatusPolicy::default();
+        policy.set(BlockStatus::NonTried, Target::Pattern);
+
+        // the devices stacked on the pattern file grow 16 times each, so 5 cover 1 TiB
+        let levels = pattern_stack_levels(mapfile.end());
+        assert_eq!(levels, 5);
+        assert_eq!(pattern_stack_size(levels), tib);
+        assert_eq!(pattern_stack_levels(tib + 512), 6);
+        assert_eq!(pattern_stack_levels(PATTERN_SIZE as u128), 0);
+
+        let below = PatternDevice {
+            device: "/dev/mapper/ddrm0-pattern1".to_string(),
+            size: pattern_stack_size(1),
+        };
+        let table = pattern_stack_table(&below);
+        assert_eq!(table.lines().count(), PATTERN_FAN_OUT as usize);
+        assert_eq!(
+            table.lines().last(),
+            Some("491520 32768 linear /dev/mapper/ddrm0-pattern1 0")
+        );
+
+        // so the whole bad area is one line of the table
+        let sources = Sources {
+            image: "/dev/loop0".to_string(),
+            pattern: Some(PatternDevice {
+                device: "/dev/mapper/ddrm0-pattern5".to_string(),
+                size: pattern_stack_size(levels),
+            }),
+        };
+        let table = create_table(&mapfile, &sources, &policy).unwrap();
+        assert_eq!(
+            table,
+            "0 8 linear /dev/loop0 0\n8 2147483640 linear /dev/mapper/ddrm0-pattern5 0\n"
+        );
+    }
+
+    #[test]
+    fn clip_mapfile() {
+        let input = include_str!("./test4.txt");
+        let mut mapfile = parse(&OsString::from("test4.txt"), input).unwrap();
+        let full = mapfile.clone();
+
+        mapfile.clip(0x3A4F2000);
+        assert_eq!(mapfile.end(), 0x3A4F2000);
+        assert_eq!(mapfile.blocks[..2], full.blocks[..2]);
+        assert_eq!(mapfile.blocks[2].size, 0x1000);
+
+        mapfile.clip(0x3A500000);
+        assert_eq!(
+            mapfile.blocks.last(),
+            Some(&Block {
+                pos: 0x3A4F2000,
+                size: 0xE000,
+                status: BlockStatus::NonTried
+            })
+        );
+    }
+
+    #[test]
+    fn window_table() {
+        let input = include_str!("./test4.txt");
+        let mapfile = parse(&OsString::from("test4.txt"), input).unwrap();
+        let window = mapfile.slice(0x3A4F0000..0x3A5C8000);
+
+        let table = create_table(
+            &window,
+            &Sources::new("/dev/loop##"),
+            &StatusPolicy::default(),
+        )
+        .unwrap();
+        let expected = "\
+0 32 error
+32 1632 linear /dev/loop## 1910688
+1664 64 error
+";
+        assert_eq!(table, expected);
+    }
+
+    #[test]
+    fn domain_restrict() {
+        let input = include_str!("./test4.txt");
+        let mapfile = parse(&OsString::from("test4.txt"), input).unwrap();
+        let domain = "0x0 +\n0x0 0x3A4F4000 ?\n0x3A4F4000 0x10000 +\n0x3A504000 0x1000 ?\n\
+                      0x3A505000 0x1000 +\n0x3A506000 0x3D0FA000 ?\n";
+        let domain = parse(&OsString::from("domain"), domain).unwrap();
+
+        let span = domain.finished_span().unwrap();
+        assert_eq!(span, 0x3A4F4000..0x3A506000);
+
+        let restricted = mapfile.restrict(&domain).slice(span);
+        let blocks: Vec<_> = restricted
+            .blocks
+            .iter()
+            .map(|b| (b.pos, b.size, b.status.as_char()))
+            .collect();
+        assert_eq!(
+            blocks,
+            vec![
+                (0x3A4F4000, 0x10000, '+'),
+                (0x3A504000, 0x1000, '?'),
+                (0x3A505000, 0x1000, '+')
+            ]
+        );
+    }
+
+    #[test]
+    fn recovered_bytes() {
+        let before = "0x0 ?\n0x0 0x1000 +\n0x1000 0x3000 -\n0x4000 0x2000 +\n0x6000 0x2000 ?\n";
+        let after = "0x0 +\n0x0 0x2000 +\n0x2000 0x2000 -\n0x4000 0x1000 /\n0x5000 0x3000 +\n";
+        let before = parse(&OsString::from("before"), before).unwrap();
+        let after = parse(&OsString::from("after"), after).unwrap();
+
+        assert_eq!(before.finished_bytes(), 0x3000);
+        assert_eq!(after.finished_bytes(), 0x5000);
+        assert_eq!(after.finished_overlap(&before), 0x2000);
+        assert_eq!(after.recovered_since(&before), 0x3000);
+        assert_eq!(before.recovered_since(&after), 0x1000);
+        assert_eq!(after.recovered_since(&after), 0);
+    }
+
+    /// Builds an MBR or extended boot record from (slot, type, start, size) entries
+    fn mbr_sector(entries: &[(usize, u8, u32, u32)]) -> Vec<u8> {
+        let mut sector = vec![0; 512];
+        for (slot, kind, start, size) in entries {
+            let entry = &mut sector[446 + slot * 16..462 + slot * 16];
+            entry[4] = *kind;
+            entry[8..12].copy_from_slice(&start.to_le_bytes());
+            entry[12..16].copy_from_slice(&size.to_le_bytes());
+        }
+        sector[510..512].copy_from_slice(&[0x55, 0xAA]);
+        sector
+    }
+
+    /// Builds a 4 MiB GPT disk with an EFI partition in slot 1 and a Linux partition in slot 3
+    fn gpt_image() -> Vec<u8> {
+        gpt_image_with(|_| (), |_, _| ())
+    }
+
+    /// Builds a GPT disk image, changing the entries and then each header before their checksums
+    fn gpt_image_with(
+        edit_entries: impl Fn(&mut [u8]),
+        edit_header: impl Fn(u64, &mut [u8]),
+    ) -> Vec<u8> {
+        let sectors = 8192u64;
+        let mut image = vec![0; sectors as usize * 512];
+        image[..512].copy_from_slice(&mbr_sector(&[(0, 0xEE, 1, sectors as u32 - 1)]));
+
+        let mut entries = vec![0u8; 128 * 128];
+        let efi = [
+            0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E,
+            0xC9, 0x3B,
+        ];
+        for (slot, kind, first, last, name) in [
+            (0, efi, 2048u64, 4095u64, "EFI"),
+            (2, [0xAF; 16], 4096, 8158, "root"),
+        ] {
+            let entry = &mut entries[slot * 128..(slot + 1) * 128];
+            entry[0..16].copy_from_slice(&kind);
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+            for (i, c) in name.encode_utf16().enumerate() {
+                entry[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+
+        edit_entries(&mut entries);
+
+        for (header_lba, entries_lba) in [(1, 2), (sectors - 1, sectors - 33)] {
+            let mut header = vec![0u8; 92];
+            header[0..8].copy_from_slice(b"EFI PART");
+            header[12..16].copy_from_slice(&92u32.to_le_bytes());
+            header[24..32].copy_from_slice(&header_lba.to_le_bytes());
+            header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+            header[80..84].copy_from_slice(&128u32.to_le_bytes());
+            header[84..88].copy_from_slice(&128u32.to_le_bytes());
+            header[88..92].copy_from_slice(&crc32fast::hash(&entries).to_le_bytes());
+            edit_header(header_lba, &mut header);
+            let crc = crc32fast::hash(&header);
+            header[16..20].copy_from_slice(&crc.to_le_bytes());
+
+            let pos = header_lba as usize * 512;
+            image[pos..pos + 92].copy_from_slice(&header);
+            let pos = entries_lba as usize * 512;
+            image[pos..pos + entries.len()].copy_from_slice(&entries);
+        }
+        image
+    }
+
+    #[test]
+    fn mbr_partitions() {
+        let mut image = vec![0; 4 * 1024 * 1024];
+        image[..512].copy_from_slice(&mbr_sector(&[(0, 0x83, 2048, 2048), (2, 0x05, 4096, 4096)]));
+        image[4096 * 512..4097 * 512]
+            .copy_from_slice(&mbr_sector(&[(0, 0x83, 2048, 1024), (1, 0x05, 3072, 1024)]));
+        image[7168 * 512..7169 * 512].copy_from_slice(&mbr_sector(&[(0, 0x07, 1, 1023)]));
+
+        let read = |pos: u64, len: usize| Some(image[pos as usize..pos as usize + len].to_vec());
+        let table = read_partitions(read, image.len() as u64, 512).unwrap();
+        assert_eq!(table.kind, TableKind::Mbr);
+
+        let partitions: Vec<_> = table
+            .partitions
+            .iter()
+            .map(|p| (p.number, p.start / 512, p.size / 512, p.kind.as_str()))
+            .collect();
+        assert_eq!(
+            partitions,
+            vec![
+                (1, 2048, 2048, "0x83"),
+                (5, 6144, 1024, "0x83"),
+                (6, 7169, 1023, "0x07")
+            ]
+        );
+    }
+
+    #[test]
+    fn gpt_backup_header() {
+        let image = gpt_image();
+        let read = |pos: u64, len: usize| Some(image[pos as usize..pos as usize + len].to_vec());
+        let primary = read_partitions(read, image.len() as u64, 512).unwrap();
+        assert_eq!(primary.kind, TableKind::Gpt);
+        assert!(!primary.backup);
+
+        let efi = &primary.partitions[0];
+        assert_eq!(efi.number, 1);
+        assert_eq!(efi.kind, "C12A7328-F81F-11D2-BA4B-00A0C93EC93B");
+        assert_eq!(efi.name.as_deref(), Some("EFI"));
+        assert_eq!(primary.partitions[1].number, 3);
+        assert_eq!(primary.partitions[1].size, (8159 - 4096) * 512);
+
+        // the primary header and the MBR are in bad sectors
+        let read = |pos: u64, len: usize| {
+            (pos >= 1024).then(|| image[pos as usize..pos as usize + len].to_vec())
+        };
+        let backup = read_partitions(read, image.len() as u64, 512).unwrap();
+        assert!(backup.backup);
+        assert_eq!(backup.partitions, primary.partitions);
+
+        let read = |_, _| None;
+        assert_eq!(
+            read_partitions(read, image.len() as u64, 512),
+            Err(PartitionError::Unreadable)
+        );
+    }
+
+    #[test]
+    fn gpt_invalid_header() {
+        let read_image = |image: &[u8]| {
+            let read = |pos: u64, len: usize| {
+                let end = (pos as usize).checked_add(len)?;
+                image.get(pos as usize..end).map(|bytes| bytes.to_vec())
+            };
+            read_partitions(read, image.len() as u64, 512)
+        };
+        let primary = read_image(&gpt_image()).unwrap();
+
+        // values in the header that are out of range make it invalid,
+        // so the backup is used if only the primary has them
+        let edits: [(usize, &[u8]); 5] = [
+            (72, &u64::MAX.to_le_bytes()),
+            (80, &u32::MAX.to_le_bytes()),
+            (84, &96u32.to_le_bytes()),
+            (84, &384u32.to_le_bytes()),
+            (84, &1024u32.to_le_bytes()),
+        ];
+        for (pos, value) in edits {
+            let edit = |lba: u64, header: &mut [u8], all: bool| {
+                if lba == 1 || all {
+                    header[pos..pos + value.len()].copy_from_slice(value);
+                }
+            };
+            let image = gpt_image_with(|_| (), |lba, header| edit(lba, header, false));
+            let backup = read_image(&image).unwrap();
+            assert!(backup.backup);
+            assert_eq!(backup.partitions, primary.partitions);
+
+            let image = gpt_image_with(|_| (), |lba, header| edit(lba, header, true));
+            assert_eq!(read_image(&image), Err(PartitionError::NoTable));
+        }
+
+        // as are entries whose position in bytes does not fit
+        for (pos, value) in [(32, u64::MAX / 2), (40, u64::MAX)] {
+            let image = gpt_image_with(
+                |entries| entries[pos..pos + 8].copy_from_slice(&value.to_le_bytes()),
+                |_, _| (),
+            );
+            assert_eq!(read_image(&image), Err(PartitionError::NoTable));
+        }
+    }
+
+    #[test]
+    fn image_partitions() {
+        let path = std::env::temp_dir().join(format!("ddr-mount-test-{}.img", std::process::id()));
+        std::fs::write(&path, gpt_image()).unwrap();
+
+        // the primary header is bad, the EFI partition has an untried area
+        // and the root partition has a bad area
+        let map = "0x0 +\n0x0 0x200 +\n0x200 0x200 -\n0x400 0x100000 +\n0x100400 0x1000 ?\n\
+                   0x101400 0x2F8C00 +\n0x3FA000 0x1000 -\n0x3FB000 0x5000 +\n";
+        let mapfile = parse(&OsString::from("gpt.map"), map).unwrap();
+        let table = read_image_partitions(&path, &mapfile, 512);
+        std::fs::remove_file(&path).unwrap();
+
+        let table = table.unwrap();
+        assert!(table.backup);
+        let health: Vec<_> = partition_health(&table, &mapfile)
+            .iter()
+            .map(|p| (p.partition.number, p.bad_bytes, p.untried_bytes))
+            .collect();
+        assert_eq!(health, vec![(1, 0, 0x1000), (3, 0x1000, 0)]);
+    }
+
+    #[test]
+    fn stats_summary() {
+        let input = include_str!("./test4.txt");
+        let mapfile = parse(&OsString::from("test4.txt"), input).unwrap();
+        let stats = Stats::new(&mapfile);
+
+        assert_eq!(stats.current_pos, 0x3A5C0000);
+        assert_eq!(stats.current_status, CurrentStatus::Scraping);
+        assert_eq!(stats.size, mapfile.end());
+        assert_eq!(stats.statuses.finished.bytes, 980205568);
+        assert_eq!(stats.statuses.finished.areas, 5);
+        assert_eq!(stats.statuses.bad_sector.bytes, 24576);
+        assert_eq!(stats.statuses.non_tried.areas, 2);
+
+        let bad = &stats.bad_extents;
+        assert_eq!((bad.count, bad.bytes), (3, 20480 + 69632 + 24576));
+        assert_eq!((bad.smallest, bad.largest), (Some(16384), Some(81920)));
+        let distribution: Vec<_> = bad.distribution.iter().map(|c| c.count).collect();
+        assert_eq!(distribution, vec![0, 0, 2, 1, 0, 0]);
+
+        let run = stats.largest_good_run.unwrap();
+        assert_eq!((run.pos, run.size), (0, 978255872));
+        assert!((stats.rescued_percent - 78.9524).abs() < 0.0001);
+    }
+
+    /// Lines of a map file that are not comments, without the whitespace around them
+    fn data_lines(contents: &str) -> Vec<&str> {
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect()
+    }
+
+    #[test]
+    fn write_round_trip() {
+        let fixtures = [
+            ("test1.txt", include_str!("./test1.txt")),
+            ("test2.txt", include_str!("./test2.txt")),
+            ("test3.txt", include_str!("./test3.txt")),
+            ("test4.txt", include_str!("./test4.txt")),
+        ];
+
+        for (name, input) in fixtures {
+            let filename = OsString::from(name);
+            let mapfile = parse(&filename, input).unwrap();
+            let written = mapfile.to_string();
+
+            assert_eq!(parse(&filename, &written).unwrap(), mapfile, "{name}");
+            assert_eq!(data_lines(&written), data_lines(input), "{name}");
+        }
+    }
+
+    #[test]
+    fn write_round_trip_generated() {
+        let statuses = [
+            BlockStatus::NonTried,
+            BlockStatus::NonTrimmed,
+            BlockStatus::NonScraped,
+            BlockStatus::BadSector,
+            BlockStatus::Finished,
+        ];
+        let current = [
+            CurrentStatus::Copying,
+            CurrentStatus::Trimming,
+            CurrentStatus::Scraping,
+            CurrentStatus::Retrying,
+            CurrentStatus::Filling,
+            CurrentStatus::Generating,
+            CurrentStatus::Finished,
+        ];
+        // xorshift, so the maps are the same on every run
+        let mut seed: u64 = 0x2545F4914F6CDD1D;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+
+        for _ in 0..200 {
+            let mut pos = 0;
+            let blocks = (0..next() % 50)
+                .map(|_| {
+                    // mix small sizes with ones too large for 32 bits
+                    let size = (next() >> (next() % 64)) as u128 * 512 + 512;
+                    let block = Block {
+                        pos,
+                        size,
+                        status: statuses[(next() % 5) as usize],
+                    };
+                    pos += size;
+                    block
+                })
+                .collect();
+            let mapfile = Mapfile {
+                status: StatusLine {
+                    current_pos: next() as u128,
+                    current_status: current[(next() % 7) as usize],
+                    current_pass: (next() % 2 == 0).then(|| (next() % 5) as u8 + 1),
+                },
+                blocks,
+            };
+
+            let mut written = Vec::new();
+            mapfile.write(&mut written).unwrap();
+            let written = String::from_utf8(written).unwrap();
+            let parsed = parse(&OsString::from("generated.txt"), &written).unwrap();
+
+            assert_eq!(parsed, mapfile);
+            assert_eq!(parsed.to_string(), written);
+        }
+    }
+
+    /// Statuses of the blocks of a map file as (pos, size, status character)
+    fn block_chars(mapfile: &Mapfile) -> Vec<(u128, u128, char)> {
+        mapfile
+            .blocks
+            .iter()
+            .map(|block| (block.pos, block.size, block.status.as_char()))
+            .collect()
+    }
+
+    #[test]
+    fn combine_maps() {
+        let first = parse(
+            &OsString::from("first.txt"),
+            "0x0 + 1\n0x0 0x1000 +\n0x1000 0x1000 -\n0x2000 0x1000 ?\n",
+        )
+        .unwrap();
+        let second = parse(
+            &OsString::from("second.txt"),
+            "0x0 ? 1\n0x0 0x1000 -\n0x1000 0x1000 +\n0x2000 0x1000 /\n0x3000 0x1000 -\n",
+        )
+        .unwrap();
+        let maps = [first.clone(), second.clone()];
+
+        let merged = combine::merge(&maps).unwrap();
+        assert_eq!(merged.status, first.status);
+        assert_eq!(
+            block_chars(&merged),
+            vec![
+                (0, 0x2000, '+'),
+                (0x2000, 0x1000, '/'),
+                (0x3000, 0x1000, '-')
+            ]
+        );
+        assert_eq!(
+            block_chars(&combine::or(&maps).unwrap()),
+            vec![(0, 0x2000, '+'), (0x2000, 0x2000, '?')]
+        );
+        assert_eq!(
+            block_chars(&combine::and(&maps).unwrap()),
+            vec![(0, 0x2000, '-'), (0x2000, 0x2000, '?')]
+        );
+        assert!(combine::merge(&[]).is_none());
+
+        let diff = combine::diff(&first, &second);
+        assert_eq!(diff.status, second.status);
+        assert_eq!(block_chars(&diff), vec![(0, 0x4000, '+')]);
+        assert_eq!(
+            block_chars(&combine::diff(&first, &first)),
+            vec![(0, 0x3000, '?')]
+        );
+        // the result is a valid map file
+        assert_eq!(
+            parse(&OsString::from("merged.txt"), &merged.to_string()).unwrap(),
+            merged
+        );
+    }
+
+    #[test]
+    fn composite_table() {
+        let first = parse(
+            &OsString::from("first.txt"),
+            "0x0 + 1\n0x0 0x400 +\n0x400 0x400 -\n0x800 0x400 ?\n0xC00 0x400 +\n",
+        )
+        .unwrap();
+        let second = parse(
+            &OsString::from("second.txt"),
+            "0x0 + 1\n0x0 0x200 -\n0x200 0x600 +\n0x800 0x800 *\n",
+        )
+        .unwrap();
+        let layers = [
+            Layer {
+                mapfile: &first,
+                device: "/dev/loop1",
+            },
+            Layer {
+                mapfile: &second,
+                device: "/dev/loop2",
+            },
+        ];
+
+        let table = create_composite_table(&layers, None, &StatusPolicy::default()).unwrap();
+        assert_eq!(
+            table,
+            "0 2 linear /dev/loop1 0\n2 2 linear /dev/loop2 2\n4 2 error\n6 2 linear /dev/loop1 6\n"
+        );
+
+        // statuses mapped to linear are read from the first image with one
+        let mut policy = StatusPolicy::default();
+        policy.set(BlockStatus::NonTrimmed, Target::Linear);
+        let table = create_composite_table(&layers, None, &policy).unwrap();
+        assert_eq!(
+            table,
+            "0 2 linear /dev/loop1 0\n2 4 linear /dev/loop2 2\n6 2 linear /dev/loop1 6\n"
+        );
+
+        // a single layer gives the same table as create_table
+        assert_eq!(
+            create_composite_table(&layers[..1], None, &StatusPolicy::default()).unwrap(),
+            create_table(
+                &first,
+                &Sources::new("/dev/loop1"),
+                &StatusPolicy::default()
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn table_errors() {
+        let input = "0x0 +\n0x0 0x400 +\n0x400 0x400 -\n";
+        let mapfile = parse(&OsString::from("table.map"), input).unwrap();
+        let shorter = mapfile.slice(0..0x400);
+        let policy = StatusPolicy::default();
+
+        assert_eq!(
+            create_composite_table(&[], None, &policy),
+            Err(TableError::NoLayers)
+        );
+        let layers = [
+            Layer {
+                mapfile: &mapfile,
+                device: "/dev/loop1",
+            },
+            Layer {
+                mapfile: &shorter,
+                device: "/dev/loop2",
+            },
+        ];
+        assert_eq!(
+            create_composite_table(&layers, None, &policy),
+            Err(TableError::LayerRange(1))
+        );
+
+        let mut policy = StatusPolicy::default();
+        policy.set(BlockStatus::BadSector, Target::Pattern);
+        assert_eq!(
+            create_table(&mapfile, &Sources::new("/dev/loop1"), &policy),
+            Err(TableError::NoPattern)
+        );
+        // the pattern device is only needed if a block is filled with the pattern
+        assert!(create_table(&shorter, &Sources::new("/dev/loop1"), &policy).is_ok());
+    }
+
+    #[test]
+    fn export_sparse_image() {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let image_path = dir.join(format!("ddr-mount-export-{id}.img"));
+        let output_path = dir.join(format!("ddr-mount-export-{id}.out"));
+        let image: Vec<u8> = (0..0x1000u32).map(|i| (i % 251) as u8 + 1).collect();
+        std::fs::write(&image_path, &image).unwrap();
+
+        let map = "0x400 +\n0x0 0x200 +\n0x200 0x400 -\n0x600 0x600 +\n0xC00 0x400 ?\n";
+        let mapfile = parse(&OsString::from("export.map"), map).unwrap();
+        let export = |mapfile: &Mapfile, fill| {
+            let image = std::fs::File::open(&image_path).unwrap();
+            let output = std::fs::File::create(&output_path).unwrap();
+            let summary = export_image(&image, &output, mapfile, fill, "BAD").unwrap();
+            (summary, std::fs::read(&output_path).unwrap())
+        };
+
+        // finished areas are copied and the rest reads as zeros
+        let (summary, output) = export(&mapfile, Fill::Zero);
+        assert_eq!(
+            summary,
+            ExportSummary {
+                copied: 0x800,
+                filled: 0x800
+            }
+        );
+        assert_eq!(output.len(), image.len());
+        assert_eq!(output[..0x200], image[..0x200]);
+        assert!(output[0x200..0x600].iter().all(|byte| *byte == 0));
+        assert_eq!(output[0x600..0xC00], image[0x600..0xC00]);
+        assert!(output[0xC00..].iter().all(|byte| *byte == 0));
+
+        // the pattern starts again at every sector of the exported image
+        let (_, output) = export(&mapfile, Fill::Pattern);
+        let sector = pattern_sector("BAD");
+        assert_eq!(output[0x200..0x400], sector);
+        assert_eq!(output[0x400..0x600], sector);
+        assert_eq!(output[0x600..0xC00], image[0x600..0xC00]);
+
+        // a slice is exported from the start of the new image, along with a map file for it
+        let slice = mapfile.slice(0x400..0x800);
+        let (summary, output) = export(&slice, Fill::Zero);
+        std::fs::remove_file(&image_path).unwrap();
+        std::fs::remove_file(&output_path).unwrap();
+
+        assert_eq!(summary.copied, 0x200);
+        assert_eq!(output.len(), 0x400);
+        assert_eq!(output[0x200..], image[0x600..0x800]);
+        assert_eq!(
+            slice.rebase().blocks,
+            vec![
+                Block {
+                    pos: 0,
+                    size: 0x200,
+                    status: BlockStatus::BadSector
+                },
+                Block {
+                    pos: 0x200,
+                    size: 0x200,
+                    status: BlockStatus::Finished
+                },
+            ]
+        );
+        assert_eq!(slice.rebase().status.current_pos, 0);
+    }
+
+    #[test]
+    fn legacy_config() {
+        // written by 1.1, as a list of devices with only the image and where it is mounted
+        let legacy = r#"[[1,{"image_file":{"Unix":[47,116,109,112,47,97,46,105,109,103]},"image_mount_point":"/dev/loop3","dm_mount_point":"ddrm1"}],[4,{"image_file":{"Unix":[98,46,105,109,103]},"image_mount_point":"/dev/loop7","dm_mount_point":"ddrm4"}]]"#;
+        let (version, devices) = parse_config(legacy).unwrap();
+
+        assert_eq!(version, 1);
+        assert_eq!(devices.keys().copied().collect::<Vec<_>>(), vec![1, 4]);
+        let first = &devices[&1];
+        assert_eq!(first.image_file, OsString::from("/tmp/a.img"));
+        assert_eq!(first.image_mount_point, "/dev/loop3");
+        assert_eq!(first.dm_mount_point, "ddrm1");
+        assert_eq!(first.map_file, None);
+        assert!(first.options.is_none() && first.layers.is_empty() && first.overlay.is_none());
+        assert_eq!(devices[&4].dm_mount_point, "ddrm4");
+
+        // the same list wrapped in the current version reads the same
+        let (version, current) =
+            parse_config(&format!(r#"{{"version":2,"devices":{legacy}}}"#)).unwrap();
+        assert_eq!(version, 2);
+        assert_eq!(
+            current
+                .values()
+                .map(|d| &d.dm_mount_point)
+                .collect::<Vec<_>>(),
+            vec!["ddrm1", "ddrm4"]
+        );
+    }
+
+    #[test]
+    fn affected_bad_bytes() {
+        let bad = [0x200..0x400, 0x400..0x600, 0x1000..0x1200];
+        let one = |extent: std::ops::Range<u64>, bad: &[std::ops::Range<u64>]| {
+            bad_bytes(std::slice::from_ref(&extent), bad)
+        };
+        // no extents, or no bad ranges, have no bad bytes
+        assert_eq!(bad_bytes(&[], &bad), 0);
+        assert_eq!(one(0..0x2000, &[]), 0);
+        // an extent ending where a bad range starts, or starting where one ends, is not bad
+        assert_eq!(
+            bad_bytes(&[0..0x200, 0x600..0x1000, 0x1200..0x1400], &bad),
+            0
+        );
+        // adjacent bad ranges are both counted
+        assert_eq!(one(0x300..0x500, &bad), 0x200);
+        // partial overlaps at either end and a range inside the extent
+        assert_eq!(bad_bytes(&[0x100..0x300, 0x500..0x1100], &bad), 0x300);
+        assert_eq!(one(0..0x2000, &bad), 0x600);
+        // overlapping extents are each counted in full
+        assert_eq!(bad_bytes(&[0x200..0x300, 0x200..0x300], &bad), 0x200);
+        // an empty extent has no bytes
+        assert_eq!(one(0x300..0x300, &bad), 0);
+    }
+
+    #[test]
+    fn affected_unescape_mount() {
+        assert_eq!(unescape_mount(b"/mnt/disk"), b"/mnt/disk");
+        assert_eq!(unescape_mount(br"/mnt/my\040disk"), b"/mnt/my disk");
+        assert_eq!(unescape_mount(br"/mnt/a\040b\011c\134d"), b"/mnt/a b\tc\\d");
+        // anything that is not a full octal escape is kept as it is
+        assert_eq!(unescape_mount(br"/mnt/a\04"), br"/mnt/a\04");
+        assert_eq!(unescape_mount(br"/mnt/a\9zz"), br"/mnt/a\9zz");
+        assert_eq!(unescape_mount(b""), b"");
+    }
+
+    #[test]
+    fn affected_file_extents() {
+        let path = std::env::temp_dir().join(format!("ddr-mount-extents-{}", std::process::id()));
+
+        let empty = std::fs::File::create(&path).unwrap();
+        let extents = file_extents(&empty);
+
+        std::fs::write(&path, vec![0xA5u8; 0x10000]).unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let written = file_extents(&file);
+        std::fs::remove_file(&path).unwrap();
+
+        // some filesystems, such as tmpfs, cannot report extents
+        let (Ok(extents), Ok(written)) = (extents, written) else {
+            return;
+        };
+        assert!(extents.is_empty());
+        let size: u64 = written.iter().map(|extent| extent.end - extent.start).sum();
+        assert!(size >= 0x10000);
+    }
 }